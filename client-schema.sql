@@ -3,5 +3,42 @@ CREATE TABLE IF NOT EXISTS files(
     path TEXT NOT NULL UNIQUE,
     hash TEXT NOT NULL,
     last_modified TEXT NOT NULL,
+    root_dir TEXT NOT NULL,
+    base_hash TEXT,
+    mode INTEGER,
+    symlink INTEGER NOT NULL DEFAULT 0,
+    size INTEGER,
+    inode INTEGER
+)
+
+CREATE TABLE IF NOT EXISTS directories(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL UNIQUE,
     root_dir TEXT NOT NULL
+)
+
+CREATE TABLE IF NOT EXISTS pending_ops(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    root_dir TEXT NOT NULL,
+    op TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt TEXT NOT NULL,
+    last_error TEXT,
+    UNIQUE(path, root_dir)
+)
+
+CREATE TABLE IF NOT EXISTS sync_cursors(
+    root_dir TEXT PRIMARY KEY,
+    cursor INTEGER NOT NULL
+)
+
+CREATE TABLE IF NOT EXISTS selective_sync(
+    path TEXT PRIMARY KEY,
+    mode TEXT NOT NULL
+)
+
+CREATE TABLE IF NOT EXISTS selective_sync_version(
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    version INTEGER NOT NULL
 )
\ No newline at end of file
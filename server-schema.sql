@@ -3,7 +3,9 @@ CREATE TABLE IF NOT EXISTS files(
     path TEXT NOT NULL UNIQUE,
     hash TEXT NOT NULL,
     last_modified TEXT NOT NULL,
-    username TEXT NOT NULL
+    username TEXT NOT NULL,
+    mode INTEGER,
+    symlink INTEGER NOT NULL DEFAULT 0
 );
 
 CREATE TABLE IF NOT EXISTS users(
@@ -12,3 +14,80 @@ CREATE TABLE IF NOT EXISTS users(
     password TEXT NOT NULL
 );
 
+
+CREATE TABLE IF NOT EXISTS file_versions(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    last_modified TEXT NOT NULL,
+    archived_at TEXT NOT NULL,
+    username TEXT NOT NULL,
+    mode INTEGER,
+    symlink INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(path, version)
+);
+
+CREATE TABLE IF NOT EXISTS blobs(
+    hash TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,
+    stored_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS manifest_chunks(
+    hash TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    chunk_hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    PRIMARY KEY(hash, position)
+);
+
+CREATE TABLE IF NOT EXISTS blob_owners(
+    hash TEXT NOT NULL,
+    username TEXT NOT NULL,
+    PRIMARY KEY(hash, username)
+);
+
+CREATE TABLE IF NOT EXISTS upload_sessions(
+    id TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    last_modified TEXT NOT NULL,
+    base_hash TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    username TEXT NOT NULL,
+    mode INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS changes(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    hash TEXT,
+    changed_at TEXT NOT NULL,
+    username TEXT NOT NULL,
+    from_path TEXT,
+    mode INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS changes_by_user ON changes(username, id);
+
+CREATE TABLE IF NOT EXISTS directories(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL,
+    username TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS trash(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    last_modified TEXT NOT NULL,
+    deleted_at TEXT NOT NULL,
+    username TEXT NOT NULL,
+    mode INTEGER,
+    symlink INTEGER NOT NULL DEFAULT 0
+);
\ No newline at end of file
@@ -1,29 +1,62 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::DateTime;
 use crate::shared::{
     models::FileRow,
     utils
 };
-use reqwest::multipart;
-use crate::shared::models::ErrorResponse;
+use futures_util::StreamExt;
+use reqwest::{multipart, StatusCode};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use crate::shared::models::{
+    ChangeList,
+    ChangesResponse,
+    ChunkCommitRequest,
+    ChunkListRequest,
+    ChunkListResponse,
+    DirectoryListResponse,
+    ErrorResponse,
+    FileListResponse,
+    FileResponse,
+    FileVersion,
+    MoveRequest,
+    PurgeResponse,
+    SessionCreateRequest,
+    SessionResponse,
+    TrashListResponse,
+    TrashedFile,
+    UploadData,
+    UploadResponse,
+    VersionListResponse
+};
 use crate::client::apis::auth;
 
+// Attempts at sending a session part before giving up until the next sync
+const SESSION_RETRIES: u64 = 3;
+
+// Limits on the files sent in one multipart request
+const UPLOAD_BATCH_FILES: usize = 100;
+const UPLOAD_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
 pub async fn delete_file(path: String) -> Result<(), Box<dyn Error>> {
     // To delete file
     let url = utils::load_url().await?;
     let client = reqwest::Client::new();
-    let (mut access_token, expires_at) = utils::load_access_token().await?;
-
-    // token expired, call refresh then call api
-    if utils::check_expiry_time(expires_at) {
-        auth::refresh_user().await?;
-        access_token = utils::load_access_token().await?.0;
-    }
+    let access_token = access_token().await?;
 
     let delete_req = client.delete(
         format!("{}/file/delete?path={}", url, path)
     ).bearer_auth(&access_token)
         .send().await?;
 
+    // Already gone from the server, which is all a delete asks for
+    if delete_req.status() == StatusCode::NOT_FOUND {
+        return Ok(());
+    }
 
     if !delete_req.status().is_success() {
         let data = delete_req.json::<ErrorResponse>().await?;
@@ -34,18 +67,207 @@ pub async fn delete_file(path: String) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub async fn upload_files(files: Vec<FileRow>) -> Result<(), Box<dyn Error>> {
-    // Upload created/modified files
+pub async fn move_file(from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    // Rename a file or directory on the server without sending its contents again
     let url = utils::load_url().await?;
     let client = reqwest::Client::new();
-    let files_form = build_file_form(files).await?;
-    let (mut access_token, expires_at) = utils::load_access_token().await?;
+    let access_token = access_token().await?;
 
-    if utils::check_expiry_time(expires_at) {
-        auth::refresh_user().await?;
-        access_token = utils::load_access_token().await?.0;
+    let move_req = client.post(
+        format!("{}/file/move", url)
+    )
+        .json(&MoveRequest::new(from.to_string(), to.to_string()))
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !move_req.status().is_success() {
+        let data = move_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to move file: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(())
+}
+
+// Per file outcome of an upload request
+#[derive(Debug, Default)]
+pub struct UploadResult {
+    pub uploaded: Vec<FileRow>,
+    pub failed: Vec<(FileRow, String)>,
+    // Local file alongside the newer server copy it conflicts with
+    pub conflicts: Vec<(FileRow, FileRow)>,
+}
+
+pub async fn create_directory(path: &str) -> Result<(), Box<dyn Error>> {
+    // Track a directory on the server, even while it's empty
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let create_req = client.post(
+        format!("{}/file/directory", url)
+    )
+        .query(&[("path", path)])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !create_req.status().is_success() {
+        let data = create_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to create directory: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(())
+}
+
+pub async fn list_directories() -> Result<Vec<String>, Box<dyn Error>> {
+    // List every directory the server tracks for this user
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let list_req = client.get(
+        format!("{}/file/directories", url)
+    )
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !list_req.status().is_success() {
+        let data = list_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to list directories: {:?}", data.error);
+        return Err(Box::from(data.error));
     }
 
+    let data = list_req.json::<DirectoryListResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn upload_files(files: Vec<FileRow>) -> Result<UploadResult, Box<dyn Error>> {
+    // Upload created/modified files, several requests at a time
+    let concurrency = utils::load_config().await?.upload_concurrency.max(1);
+
+    // Refresh an expired token once up front rather than in every request
+    access_token().await?;
+
+    let results = futures_util::stream::iter(split_batches(files).await)
+        .map(upload_batch)
+        .buffer_unordered(concurrency)
+        .collect::<Vec<UploadResult>>()
+        .await;
+
+    let mut result = UploadResult::default();
+    for batch_result in results {
+        result.uploaded.extend(batch_result.uploaded);
+        result.failed.extend(batch_result.failed);
+        result.conflicts.extend(batch_result.conflicts);
+    }
+
+    Ok(result)
+}
+
+// How a chunked or resumable upload of one file ended
+enum FileUpload {
+    // Hash of the contents that went up, read fresh so it can be newer than the one recorded
+    Uploaded(String),
+    // The server's copy the file conflicts with
+    Conflict(FileRow),
+}
+
+// The files sent in one request, or one file's chunked or resumable upload
+enum UploadBatch {
+    Form(Vec<FileRow>),
+    Session(FileRow),
+    Chunked(FileRow),
+}
+
+// Large files go up chunk by chunk so only changed chunks are sent, medium ones through a resumable
+// session, the rest share multipart requests of bounded size
+async fn split_batches(files: Vec<FileRow>) -> Vec<UploadBatch> {
+    let mut batches = Vec::new();
+    let mut form_batches: Vec<(Vec<FileRow>, u64, HashSet<String>)> = Vec::new();
+
+    for file in files {
+        // A link synced as a link is only as big as its target's path
+        let size = match fs::symlink_metadata(file.path()).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+
+        if size >= utils::CHUNKED_UPLOAD_THRESHOLD {
+            batches.push(UploadBatch::Chunked(file));
+            continue;
+        }
+
+        if size >= utils::RESUMABLE_UPLOAD_THRESHOLD {
+            batches.push(UploadBatch::Session(file));
+            continue;
+        }
+
+        // The server keys results by filename, so a request can't carry two files with the same name
+        let (filename, _) = extract_filename_filepath(&file.path().to_string());
+        let filename = sanitize_filename::sanitize(filename);
+
+        let batch = form_batches.iter_mut().find(|(batch, bytes, names)| {
+            batch.len() < UPLOAD_BATCH_FILES && bytes + size <= UPLOAD_BATCH_BYTES && !names.contains(&filename)
+        });
+
+        match batch {
+            Some((batch, bytes, names)) => {
+                batch.push(file);
+                *bytes += size;
+                names.insert(filename);
+            }
+            None => form_batches.push((vec![file], size, HashSet::from([filename]))),
+        }
+    }
+
+    batches.extend(form_batches.into_iter().map(|(batch, _, _)| UploadBatch::Form(batch)));
+    batches
+}
+
+// Upload one batch, a failed request fails each of its files rather than the whole upload
+async fn upload_batch(batch: UploadBatch) -> UploadResult {
+    let mut result = UploadResult::default();
+
+    match batch {
+        UploadBatch::Form(files) => match upload_form(files.clone()).await {
+            Ok(form_result) => result = form_result,
+            Err(e) => {
+                let error = e.to_string();
+                result.failed = files.into_iter().map(|file| (file, error.clone())).collect();
+            }
+        },
+        UploadBatch::Session(file) => record_file_upload(&mut result, file, upload_resumable).await,
+        UploadBatch::Chunked(file) => record_file_upload(&mut result, file, upload_chunked).await,
+    }
+
+    result
+}
+
+// Upload one file on its own, reporting it under the hash the server now holds
+async fn record_file_upload<F, Fut>(result: &mut UploadResult, file: FileRow, upload: F)
+where
+    F: FnOnce(FileRow) -> Fut,
+    Fut: Future<Output = Result<FileUpload, Box<dyn Error>>>,
+{
+    match upload(file.clone()).await {
+        Ok(FileUpload::Uploaded(hash)) => {
+            let mut file = file;
+            file.set_hash(hash);
+            result.uploaded.push(file);
+        }
+        Ok(FileUpload::Conflict(server_file)) => result.conflicts.push((file, server_file)),
+        Err(e) => result.failed.push((file, e.to_string())),
+    }
+}
+
+// Upload files in a single multipart request
+async fn upload_form(files: Vec<FileRow>) -> Result<UploadResult, Box<dyn Error>> {
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let files_form = build_file_form(&files).await?;
+    let access_token = access_token().await?;
+
     let upload_req = client.post(
         format!("{}/file/upload", url)
     )
@@ -53,37 +275,480 @@ pub async fn upload_files(files: Vec<FileRow>) -> Result<(), Box<dyn Error>> {
         .multipart(files_form)
         .send().await?;
 
-    if !upload_req.status().is_success() {
+    // A conflict still carries the results for every file in the request
+    if !upload_req.status().is_success() && upload_req.status() != StatusCode::CONFLICT {
         let data = upload_req.json::<ErrorResponse>().await?;
         eprintln!("Failed to upload files: {:?}", data.error);
         return Err(Box::from(data.error));
     }
 
+    let data = upload_req.json::<UploadResponse>().await?;
 
-    Ok(())
+    Ok(collect_upload_results(files, data.data))
+}
+
+// Upload a file as content defined chunks, sending only those the server lacks
+async fn upload_chunked(file: FileRow) -> Result<FileUpload, Box<dyn Error>> {
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let path = PathBuf::from(file.path());
+    let (hash, chunks) = tokio::task::spawn_blocking(move || utils::chunk_filepath(&path)).await??;
+
+    let missing_req = client.post(
+        format!("{}/file/chunks/missing", url)
+    )
+        .bearer_auth(&access_token)
+        .json(&ChunkListRequest { hashes: chunks.iter().map(|(_, chunk)| chunk.hash().to_string()).collect() })
+        .send().await?;
+
+    if !missing_req.status().is_success() {
+        let data = missing_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to check chunks: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let missing = missing_req.json::<ChunkListResponse>().await?.data.into_iter().collect::<HashSet<String>>();
+
+    let mut f = fs::File::open(file.path()).await?;
+    let mut sent = HashSet::new();
+
+    for (offset, chunk) in &chunks {
+        // A chunk repeated within the file only needs sending once
+        if !missing.contains(chunk.hash()) || !sent.insert(chunk.hash()) {
+            continue;
+        }
+
+        let mut data = vec![0u8; chunk.size() as usize];
+        f.seek(SeekFrom::Start(*offset)).await?;
+        f.read_exact(&mut data).await?;
+
+        let chunk_req = client.put(
+            format!("{}/file/chunk", url)
+        )
+            .query(&[("hash", chunk.hash())])
+            .bearer_auth(&access_token)
+            .body(data)
+            .send().await?;
+
+        if !chunk_req.status().is_success() {
+            let data = chunk_req.json::<ErrorResponse>().await?;
+            eprintln!("Failed to upload chunk: {:?}", data.error);
+            return Err(Box::from(data.error));
+        }
+    }
+
+    let chunks = chunks.into_iter().map(|(_, chunk)| chunk).collect();
+    let commit_req = client.post(
+        format!("{}/file/chunks/commit", url)
+    )
+        .bearer_auth(&access_token)
+        .json(&ChunkCommitRequest::new(&file, hash.clone(), chunks))
+        .send().await?;
+
+    if commit_req.status() == StatusCode::CONFLICT {
+        let data = commit_req.json::<FileResponse>().await?;
+        return Ok(FileUpload::Conflict(data.data));
+    }
+
+    if !commit_req.status().is_success() {
+        let data = commit_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to commit chunks: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(FileUpload::Uploaded(hash))
+}
+
+// Upload a file through an upload session, picking up where an earlier attempt left off
+async fn upload_resumable(file: FileRow) -> Result<FileUpload, Box<dyn Error>> {
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let size = fs::metadata(file.path()).await?.len();
+    let path = PathBuf::from(file.path());
+    let hash = match tokio::task::spawn_blocking(move || utils::hash_filepath(&path)).await? {
+        Some(hash) => hash,
+        None => return Err(Box::from(format!("Failed to hash {}", file.path())))
+    };
+
+    // The server hands back the session already open for these contents, if there is one
+    let session_req = client.post(
+        format!("{}/file/session", url)
+    )
+        .bearer_auth(&access_token)
+        .json(&SessionCreateRequest::new(&file, hash.clone(), size))
+        .send().await?;
+
+    if !session_req.status().is_success() {
+        let data = session_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to start upload session: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let session = session_req.json::<SessionResponse>().await?.data;
+    let mut offset = session.offset;
+    let mut retries = 0;
+    let mut f = fs::File::open(file.path()).await?;
+
+    if offset > 0 {
+        println!("Resuming upload of {} from byte {}", file.path(), offset);
+    }
+
+    while offset < size {
+        let mut data = vec![0u8; (size - offset).min(utils::SESSION_PART_SIZE) as usize];
+        f.seek(SeekFrom::Start(offset)).await?;
+        f.read_exact(&mut data).await?;
+
+        match send_session_part(&client, &url, &access_token, &session.id, offset, data).await {
+            Ok(received) => {
+                offset = received;
+                retries = 0;
+            }
+            Err(e) => {
+                retries += 1;
+                if retries > SESSION_RETRIES {
+                    return Err(e);
+                }
+
+                // Ask the server how much it kept before trying again
+                eprintln!("Upload of {} interrupted, retrying: {}", file.path(), e);
+                tokio::time::sleep(Duration::from_secs(retries)).await;
+                offset = session_offset(&client, &url, &access_token, &session.id).await?;
+            }
+        }
+    }
+
+    let finalize_req = client.post(
+        format!("{}/file/session/finalize", url)
+    )
+        .query(&[("id", &session.id)])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if finalize_req.status() == StatusCode::CONFLICT {
+        let data = finalize_req.json::<FileResponse>().await?;
+        return Ok(FileUpload::Conflict(data.data));
+    }
+
+    if !finalize_req.status().is_success() {
+        let data = finalize_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to finish upload: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(FileUpload::Uploaded(hash))
 }
 
+pub async fn list_files() -> Result<Vec<FileRow>, Box<dyn Error>> {
+    // List every file the server holds for this user
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let list_req = client.get(
+        format!("{}/file/list", url)
+    )
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !list_req.status().is_success() {
+        let data = list_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to list files: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = list_req.json::<FileListResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn list_changes(since: Option<i64>) -> Result<ChangeList, Box<dyn Error>> {
+    // Changes recorded after the since cursor, or just the latest cursor without one
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let mut changes_req = client.get(
+        format!("{}/file/changes", url)
+    );
+
+    if let Some(since) = since {
+        changes_req = changes_req.query(&[("since", since)]);
+    }
+
+    let changes_req = changes_req
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !changes_req.status().is_success() {
+        let data = changes_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to list changes: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = changes_req.json::<ChangesResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn download_file(path: String, dest: &Path, root: &Path) -> Result<FileRow, Box<dyn Error>> {
+    // Download a file from the server into dest under the watched root, returning the server's metadata for it
+    let config = utils::load_config().await?;
+    let url = config.url;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let download_req = client.get(
+        format!("{}/file/download", url)
+    )
+        .query(&[("path", &path)])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !download_req.status().is_success() {
+        let data = download_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to download file: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let hash = header_value(&download_req, utils::FILE_HASH_HEADER)?;
+    let last_modified = DateTime::parse_from_rfc3339(&header_value(&download_req, utils::LAST_MODIFIED_HEADER)?)?;
+    let mode = match header_value(&download_req, utils::FILE_MODE_HEADER) {
+        Ok(mode) => Some(u32::from_str_radix(&mode, 8)?),
+        Err(_) => None,
+    };
+    let symlink = header_value(&download_req, utils::SYMLINK_HEADER).is_ok_and(|value| value == "true");
+
+    // A linked directory along the way could otherwise send the write somewhere outside the root
+    let parent = dest.parent().ok_or_else(|| format!("No parent directory for {}", dest.display()))?;
+    fs::create_dir_all(parent).await?;
+    let parent = fs::canonicalize(parent).await?;
+    let root = fs::canonicalize(root).await?;
+    if !parent.starts_with(&root) {
+        return Err(Box::from(format!("Refusing to write {} outside the watched directory", path)));
+    }
+    let dest = parent.join(dest.file_name().ok_or_else(|| format!("No file name in {}", dest.display()))?);
+    let dest = dest.as_path();
+
+    // Write to a temp file first so a partial download never replaces the real file
+    let temp_path = utils::temp_file_path(dest);
+    if let Err(e) = write_stream(download_req, &temp_path).await {
+        fs::remove_file(&temp_path).await.ok();
+        return Err(e);
+    }
+
+    if utils::hash_filepath(&temp_path).as_deref() != Some(hash.as_str()) {
+        fs::remove_file(&temp_path).await.ok();
+        return Err(Box::from(format!("Hash mismatch downloading {}", path)));
+    }
+
+    if symlink {
+        // The contents are where the link points, the link takes the place of whatever was there
+        let target = fs::read(&temp_path).await;
+        fs::remove_file(&temp_path).await.ok();
+        let target = target?;
+
+        if !config.allow_external_links && !utils::resolve_link_target(dest, &target).starts_with(&root) {
+            return Err(Box::from(format!("Refusing to create link {} pointing outside the watched directory", path)));
+        }
+
+        if fs::symlink_metadata(dest).await.is_ok() {
+            fs::remove_file(dest).await?;
+        }
+        utils::create_symlink(&target, dest)?;
+    } else {
+        fs::rename(&temp_path, dest).await?;
+
+        // The file's contents are in place either way, so a failure here isn't worth failing the download over
+        utils::apply_file_metadata(dest, mode, last_modified.to_utc()).unwrap_or_else(|e| {
+            eprintln!("Error restoring permissions and modified time of {}. {}", path, e);
+        });
+    }
+
+    let mut file_row = utils::convert_to_file_row(path, hash.clone(), last_modified.to_utc());
+    file_row.set_base_hash(Some(hash));
+    file_row.set_mode(mode);
+    file_row.set_symlink(symlink);
+    if let Ok(metadata) = fs::symlink_metadata(dest).await {
+        file_row.set_size(Some(metadata.len()));
+        file_row.set_inode(utils::file_inode(&metadata));
+    }
+
+    Ok(file_row)
+}
+
+pub async fn list_versions(path: &str) -> Result<Vec<FileVersion>, Box<dyn Error>> {
+    // List the stored history of a file, newest first
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let versions_req = client.get(
+        format!("{}/file/versions", url)
+    )
+        .query(&[("path", path)])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !versions_req.status().is_success() {
+        let data = versions_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to list versions: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = versions_req.json::<VersionListResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn restore_version(path: &str, version: i64) -> Result<FileRow, Box<dyn Error>> {
+    // Make a stored version the current contents of a file
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let restore_req = client.post(
+        format!("{}/file/version/restore", url)
+    )
+        .query(&[("path", path), ("version", &version.to_string())])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !restore_req.status().is_success() {
+        let data = restore_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to restore version: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = restore_req.json::<FileResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn list_trash() -> Result<Vec<TrashedFile>, Box<dyn Error>> {
+    // List deleted files the server still holds, most recently deleted first
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let trash_req = client.get(
+        format!("{}/file/trash", url)
+    )
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !trash_req.status().is_success() {
+        let data = trash_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to list trash: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = trash_req.json::<TrashListResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn restore_trashed(id: i64) -> Result<FileRow, Box<dyn Error>> {
+    // Put a deleted file back on the server, watching clients download it again
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let restore_req = client.post(
+        format!("{}/file/trash/restore", url)
+    )
+        .query(&[("id", id)])
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !restore_req.status().is_success() {
+        let data = restore_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to restore file: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = restore_req.json::<FileResponse>().await?;
+    Ok(data.data)
+}
+
+pub async fn purge_trash() -> Result<usize, Box<dyn Error>> {
+    // Permanently remove everything in the trash
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let purge_req = client.delete(
+        format!("{}/file/trash/purge", url)
+    )
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !purge_req.status().is_success() {
+        let data = purge_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to empty trash: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    let data = purge_req.json::<PurgeResponse>().await?;
+    Ok(data.data.purged)
+}
 
 // Utility functions for file uploads
 
 // Build file multipart form
-async fn build_file_form(files: Vec<FileRow>) -> Result<multipart::Form, Box<dyn Error>> {
+async fn build_file_form(files: &[FileRow]) -> Result<multipart::Form, Box<dyn Error>> {
     let mut form = multipart::Form::new();
 
     for file in files {
         let (filename, path) = extract_filename_filepath(&file.path().to_string());
-        let file_part = multipart::Part::file(file.path()).await?;
+        let file_part = if file.is_symlink() {
+            multipart::Part::bytes(utils::link_target_bytes(Path::new(file.path()))?).file_name(filename.clone())
+        } else {
+            multipart::Part::file(file.path()).await?
+        };
         let last_modified_part = multipart::Part::text(file.last_modified().to_rfc3339());
         let path_part = multipart::Part::text(path);
+        let base_hash_part = multipart::Part::text(file.base_hash().unwrap_or("").to_string());
+        let hash_part = multipart::Part::text(file.hash().to_string());
         form = form
             .part(format!("last_modified_{}", filename), last_modified_part)
             .part(format!("path_{}", filename), path_part)
-            .part(format!("file_{}", filename), file_part);
+            .part(format!("base_hash_{}", filename), base_hash_part)
+            .part(format!("hash_{}", filename), hash_part);
+
+        if let Some(mode) = file.mode() {
+            form = form.part(format!("mode_{}", filename), multipart::Part::text(format!("{:o}", mode)));
+        }
+
+        if file.is_symlink() {
+            form = form.part(format!("symlink_{}", filename), multipart::Part::text("true"));
+        }
+
+        form = form.part(format!("file_{}", filename), file_part);
     }
     Ok(form)
 
 }
 
+// Match the server's per file results (keyed by sanitized filename) back to the uploaded rows
+fn collect_upload_results(files: Vec<FileRow>, data: UploadData) -> UploadResult {
+    let mut result = UploadResult::default();
+
+    for file in files {
+        let (filename, _) = extract_filename_filepath(&file.path().to_string());
+        let filename = sanitize_filename::sanitize(filename);
+
+        if data.uploaded.contains_key(&filename) {
+            result.uploaded.push(file);
+        } else if let Some(server_file) = data.conflicts.get(&filename) {
+            result.conflicts.push((file, server_file.clone()));
+        } else {
+            let error = data.failed.get(&filename).cloned().unwrap_or(String::from("No result from server"));
+            result.failed.push((file, error));
+        }
+    }
+
+    result
+}
+
 // Extract filename and filepath from filerow object
 fn extract_filename_filepath(full_path: &String) -> (String, String) {
     let mut path_tokens = full_path.split("/").collect::<Vec<&str>>();
@@ -92,3 +757,175 @@ fn extract_filename_filepath(full_path: &String) -> (String, String) {
 
     (file_name.to_string(), path)
 }
+
+// Utility functions for requests
+
+// Send part of an upload session's data, returning how much of the file the server now has
+async fn send_session_part(client: &reqwest::Client, url: &str, access_token: &str, id: &str, offset: u64, data: Vec<u8>) -> Result<u64, Box<dyn Error>> {
+    let part_req = client.put(
+        format!("{}/file/session", url)
+    )
+        .query(&[("id", id), ("offset", &offset.to_string())])
+        .bearer_auth(access_token)
+        .body(data)
+        .send().await?;
+
+    // A conflict means the server is at a different offset, which it reports
+    if !part_req.status().is_success() && part_req.status() != StatusCode::CONFLICT {
+        let data = part_req.json::<ErrorResponse>().await?;
+        return Err(Box::from(data.error));
+    }
+
+    Ok(part_req.json::<SessionResponse>().await?.data.offset)
+}
+
+async fn session_offset(client: &reqwest::Client, url: &str, access_token: &str, id: &str) -> Result<u64, Box<dyn Error>> {
+    let status_req = client.get(
+        format!("{}/file/session", url)
+    )
+        .query(&[("id", id)])
+        .bearer_auth(access_token)
+        .send().await?;
+
+    if !status_req.status().is_success() {
+        let data = status_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to fetch upload session: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(status_req.json::<SessionResponse>().await?.data.offset)
+}
+
+pub async fn open_event_stream() -> Result<reqwest::Response, Box<dyn Error>> {
+    // Subscribe to the server's stream of changes to this user's files
+    let url = utils::load_url().await?;
+    let client = reqwest::Client::new();
+    let access_token = access_token().await?;
+
+    let events_req = client.get(
+        format!("{}/events", url)
+    )
+        .header(reqwest::header::ACCEPT, "text/event-stream")
+        .bearer_auth(&access_token)
+        .send().await?;
+
+    if !events_req.status().is_success() {
+        let data = events_req.json::<ErrorResponse>().await?;
+        eprintln!("Failed to subscribe to events: {:?}", data.error);
+        return Err(Box::from(data.error));
+    }
+
+    Ok(events_req)
+}
+
+// Load access token, refreshing it first if it has expired
+async fn access_token() -> Result<String, Box<dyn Error>> {
+    let (access_token, expires_at) = utils::load_access_token().await?;
+
+    if utils::check_expiry_time(expires_at) {
+        auth::refresh_user().await?;
+        return Ok(utils::load_access_token().await?.0);
+    }
+
+    Ok(access_token)
+}
+
+fn header_value(response: &reqwest::Response, name: &str) -> Result<String, Box<dyn Error>> {
+    match response.headers().get(name) {
+        Some(value) => Ok(value.to_str()?.to_string()),
+        None => Err(Box::from(format!("Missing {} header", name)))
+    }
+}
+
+async fn write_stream(response: reqwest::Response, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let mut f = fs::File::create(dest).await?;
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        f.write_all(&chunk?).await?;
+    }
+
+    f.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    const MB: u64 = 1024 * 1024;
+
+    // Sparse files of the given sizes under a fresh temp directory
+    fn make_files(files: &[(&str, u64)]) -> (PathBuf, Vec<FileRow>) {
+        let dir = std::env::temp_dir().join(format!("rustysync-batches-{}", Uuid::new_v4()));
+        let mut rows = Vec::new();
+
+        for (name, size) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::File::create(&path).unwrap().set_len(*size).unwrap();
+            rows.push(utils::convert_to_file_row(path.to_string_lossy().to_string(), String::new(), Utc::now()));
+        }
+
+        (dir, rows)
+    }
+
+    // Kind and file count of each batch, in order
+    fn describe(batches: &[UploadBatch]) -> Vec<(&'static str, usize)> {
+        batches.iter().map(|batch| match batch {
+            UploadBatch::Form(files) => ("form", files.len()),
+            UploadBatch::Session(_) => ("session", 1),
+            UploadBatch::Chunked(_) => ("chunked", 1),
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn size_thresholds_pick_the_upload_kind() {
+        let (dir, files) = make_files(&[
+            ("chunked.bin", 8 * MB),
+            ("session_large.bin", 8 * MB - 1),
+            ("session.bin", MB),
+            ("form.bin", MB - 1),
+        ]);
+
+        let batches = split_batches(files).await;
+        std::fs::remove_dir_all(dir).ok();
+
+        assert_eq!(describe(&batches), vec![("chunked", 1), ("session", 1), ("session", 1), ("form", 1)]);
+    }
+
+    #[tokio::test]
+    async fn form_batches_are_capped_by_file_count() {
+        let names = (0..UPLOAD_BATCH_FILES + 1).map(|i| format!("{}.txt", i)).collect::<Vec<String>>();
+        let (dir, files) = make_files(&names.iter().map(|name| (name.as_str(), 10)).collect::<Vec<_>>());
+
+        let batches = split_batches(files).await;
+        std::fs::remove_dir_all(dir).ok();
+
+        assert_eq!(describe(&batches), vec![("form", UPLOAD_BATCH_FILES), ("form", 1)]);
+    }
+
+    #[tokio::test]
+    async fn form_batches_are_capped_by_size() {
+        // Each file is just under the resumable threshold, so 64 fit in the byte limit and the 65th doesn't
+        let names = (0..65).map(|i| format!("{}.bin", i)).collect::<Vec<String>>();
+        let (dir, files) = make_files(&names.iter().map(|name| (name.as_str(), MB - 1)).collect::<Vec<_>>());
+
+        let batches = split_batches(files).await;
+        std::fs::remove_dir_all(dir).ok();
+
+        assert_eq!(describe(&batches), vec![("form", 64), ("form", 1)]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_filenames_go_in_separate_batches() {
+        let (dir, files) = make_files(&[("a/notes.txt", 10), ("b/notes.txt", 10), ("c/other.txt", 10)]);
+
+        let batches = split_batches(files).await;
+        std::fs::remove_dir_all(dir).ok();
+
+        assert_eq!(describe(&batches), vec![("form", 2), ("form", 1)]);
+    }
+}
@@ -6,8 +6,7 @@ use crate::client::{
 use std::path::{ PathBuf };
 use tokio::fs;
 use std::error::Error;
-use serde_json::json;
-use tokio::io::AsyncWriteExt;
+use crate::shared::models::{Config, ConflictPolicy, SelectiveMode, SymlinkPolicy};
 use crate::shared::utils;
 
 pub async fn run_client(path: PathBuf) {
@@ -25,24 +24,143 @@ pub async fn run_client(path: PathBuf) {
 }
 
 pub async fn save_url(url: &str) -> Result<(), Box<dyn Error>> {
-    let config_dir = match utils::get_config_path().await {
-        Some(path) => path,
-        None => {
-            eprintln!("Error finding config directory");
-            return Err(Box::from("Error finding config directory"));
+    let config_dir = config_dir().await?;
+
+    // Keep any other settings already in the config
+    let existing = if config_dir.join("config.json").exists() {
+        utils::load_config().await.ok()
+    } else {
+        None
+    };
+
+    let config = match existing {
+        Some(mut config) => {
+            config.url = url.to_string();
+            config
+        }
+        None => Config {
+            url: url.to_string(),
+            conflict_policy: ConflictPolicy::default(),
+            upload_concurrency: 4,
+            symlink_policy: SymlinkPolicy::default(),
+            rescan_interval: 300,
+            allow_external_links: false,
         }
     };
-    let mut config_file = fs::File::options().create(true).write(true).open(config_dir.join("config.json")).await?;
 
-    let config = json!({
-        "url": url,
-    });
+    save_config(&config).await
+}
+
+pub async fn save_conflict_policy(policy: ConflictPolicy) -> Result<(), Box<dyn Error>> {
+    let mut config = utils::load_config().await?;
+    config.conflict_policy = policy;
+
+    save_config(&config).await
+}
+
+pub async fn save_upload_concurrency(concurrency: usize) -> Result<(), Box<dyn Error>> {
+    if concurrency == 0 {
+        return Err(Box::from("Concurrency must be at least 1"));
+    }
+
+    let mut config = utils::load_config().await?;
+    config.upload_concurrency = concurrency;
+
+    save_config(&config).await
+}
+
+pub async fn save_symlink_policy(policy: SymlinkPolicy) -> Result<(), Box<dyn Error>> {
+    let mut config = utils::load_config().await?;
+    config.symlink_policy = policy;
+
+    save_config(&config).await
+}
+
+pub async fn save_allow_external_links(allow: bool) -> Result<(), Box<dyn Error>> {
+    let mut config = utils::load_config().await?;
+    config.allow_external_links = allow;
+
+    save_config(&config).await
+}
+
+pub async fn save_rescan_interval(seconds: u64) -> Result<(), Box<dyn Error>> {
+    let mut config = utils::load_config().await?;
+    config.rescan_interval = seconds;
+
+    save_config(&config).await
+}
+
+// Exclude or include a remote folder, as it's stored in the DB (e.g. data/photos)
+pub fn set_selective_rule(path: &str, mode: SelectiveMode) -> Result<(), Box<dyn Error>> {
+    let path = selective_path(path)?;
+    let conn = db::init_db()?;
+
+    db::set_selective_rule(&conn, &path, mode)?;
+    // Anything newly included has to come from the full listing, the journal only has later changes
+    db::clear_cursors(&conn)?;
+
+    match mode {
+        SelectiveMode::Exclude => println!("Excluded {} from sync", path),
+        SelectiveMode::Include => println!("Included {} in sync", path),
+    }
+
+    Ok(())
+}
+
+pub fn remove_selective_rule(path: &str) -> Result<(), Box<dyn Error>> {
+    let path = selective_path(path)?;
+    let conn = db::init_db()?;
+
+    if !db::remove_selective_rule(&conn, &path)? {
+        return Err(Box::from(format!("No selective sync rule for {}", path)));
+    }
+    db::clear_cursors(&conn)?;
 
-    let config_string = serde_json::to_string_pretty(&config)?;
+    println!("Removed selective sync rule for {}", path);
+    Ok(())
+}
 
-    config_file.write_all(config_string.as_bytes()).await?;
+pub fn list_selective_rules() -> Result<(), Box<dyn Error>> {
+    let conn = db::init_db()?;
+    let rules = db::get_selective_rules(&conn)?;
+
+    if rules.is_empty() {
+        println!("Everything is synced");
+    }
+
+    for rule in rules {
+        println!("{} {}", rule.mode().as_str(), rule.path());
+    }
 
     Ok(())
+}
 
+fn selective_path(path: &str) -> Result<String, Box<dyn Error>> {
+    let path = utils::format_file_path(&path.to_string());
+    let path = path.trim_end_matches('/');
 
-}
\ No newline at end of file
+    if path.is_empty() || path.starts_with('/') || path.split('/').any(|part| part == "..") {
+        return Err(Box::from(format!("Invalid path {}", path)));
+    }
+
+    Ok(path.to_string())
+}
+
+async fn save_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    let config_dir = config_dir().await?;
+    let config_string = serde_json::to_string_pretty(config)?;
+
+    fs::write(config_dir.join("config.json"), config_string.as_bytes()).await?;
+
+    Ok(())
+}
+
+async fn config_dir() -> Result<PathBuf, Box<dyn Error>> {
+    match utils::get_config_path().await {
+        Some(path) => Ok(path),
+        None => {
+            eprintln!("Error finding config directory");
+            Err(Box::from("Error finding config directory"))
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::path::Path;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use rusqlite::{Connection, params };
-use crate::shared::models::FileRow;
+use crate::shared::models::{FileRow, OpKind, PendingOp, SelectiveMode, SelectiveRule};
 use crate::shared::errors::{ DbError };
 use crate::shared::utils;
 pub fn init_db() -> Result<Connection, DbError> {
@@ -13,35 +13,121 @@ pub fn init_db() -> Result<Connection, DbError> {
             path TEXT NOT NULL UNIQUE,
             hash TEXT NOT NULL,
             last_modified TEXT NOT NULL,
+            root_dir TEXT NOT NULL,
+            base_hash TEXT
+        )",
+        params![],
+    )?;
+
+    // Files tracked before base hashes existed are assumed to match the server
+    if utils::ensure_column(&conn, "files", "base_hash", "TEXT")? {
+        conn.execute("UPDATE files SET base_hash=hash", params![])?;
+    }
+
+    // Permission bits, unknown until the file is next looked at
+    utils::ensure_column(&conn, "files", "mode", "INTEGER")?;
+    // Symbolic links synced as links rather than followed
+    utils::ensure_column(&conn, "files", "symlink", "INTEGER NOT NULL DEFAULT 0")?;
+    utils::ensure_column(&conn, "files", "size", "INTEGER")?;
+    utils::ensure_column(&conn, "files", "inode", "INTEGER")?;
+
+    // Directories are tracked on their own so empty ones sync too
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS directories(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL UNIQUE,
             root_dir TEXT NOT NULL
         )",
         params![],
     )?;
+
+    // Uploads and deletes waiting on the server, kept until it acknowledges them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pending_ops(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            root_dir TEXT NOT NULL,
+            op TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt TEXT NOT NULL,
+            last_error TEXT,
+            UNIQUE(path, root_dir)
+        )",
+        params![],
+    )?;
+
+    // How far through the server's change journal each watched directory has got
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_cursors(
+            root_dir TEXT PRIMARY KEY,
+            cursor INTEGER NOT NULL
+        )",
+        params![],
+    )?;
+
+    // Remote subtrees left out of or brought back into the local copy
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS selective_sync(
+            path TEXT PRIMARY KEY,
+            mode TEXT NOT NULL
+        )",
+        params![],
+    )?;
+
+    // Bumped on every selective sync edit, so a running watcher can tell the rules changed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS selective_sync_version(
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            version INTEGER NOT NULL
+        )",
+        params![],
+    )?;
+
     Ok(conn)
 }
 
 pub fn insert_file(conn: &Connection, file_row: &FileRow, root_dir: &String) -> Result<(), DbError> {
     let mut statement = conn.prepare(
-        "INSERT INTO files(path, hash, last_modified, root_dir)\
-            VALUES (?1, ?2, ?3, ?4)"
+        "INSERT INTO files(path, hash, last_modified, root_dir, base_hash, mode, symlink, size, inode)\
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
     )?;
 
-    statement.execute(params![file_row.path(), file_row.hash(), file_row.last_modified().to_rfc3339(), root_dir])?;
+    statement.execute(params![file_row.path(), file_row.hash(), file_row.last_modified().to_rfc3339(), root_dir, file_row.base_hash(), file_row.mode(), file_row.is_symlink(), file_row.size(), file_row.inode()])?;
     Ok(())
 }
 
 pub fn update_file(conn: &Connection, file_row: &FileRow, root_dir: &String) -> Result<(), DbError> {
     let mut statement = conn.prepare(
-        "UPDATE files SET last_modified=?1, hash=?2 WHERE path=?3 AND root_dir=?4"
+        "UPDATE files SET last_modified=?1, hash=?2, base_hash=?3, mode=?4, symlink=?5, size=?6, inode=?7 WHERE path=?8 AND root_dir=?9"
+    )?;
+
+    statement.execute(params![file_row.last_modified().to_rfc3339(), file_row.hash(), file_row.base_hash(), file_row.mode(), file_row.is_symlink(), file_row.size(), file_row.inode(), file_row.path(), root_dir])?;
+    Ok(())
+}
+
+// Record the hash the server acknowledged for a file
+pub fn set_base_hash(conn: &Connection, path: &String, base_hash: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE files SET base_hash=?1 WHERE path=?2 AND root_dir=?3"
+    )?;
+
+    statement.execute(params![base_hash, path, root_dir])?;
+    Ok(())
+}
+
+// Forget what the server acknowledged for a file, so it is uploaded as new
+pub fn clear_base_hash(conn: &Connection, path: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE files SET base_hash=NULL WHERE path=?1 AND root_dir=?2"
     )?;
 
-    statement.execute(params![file_row.last_modified().to_rfc3339(), file_row.hash(), file_row.path(), root_dir])?;
+    statement.execute(params![path, root_dir])?;
     Ok(())
 }
 
 pub fn get_file(conn: &Connection, path: &String, root_dir: &String) -> Result<Vec<FileRow>, DbError> {
     let mut statement = conn.prepare(
-        "SELECT path, hash, last_modified FROM files WHERE path=?1 AND root_dir=?2"
+        "SELECT path, hash, last_modified, base_hash, mode, symlink, size, inode FROM files WHERE path=?1 AND root_dir=?2"
     )?;
 
     let mut rows = statement.query(params![path, root_dir])?;
@@ -50,11 +136,16 @@ pub fn get_file(conn: &Connection, path: &String, root_dir: &String) -> Result<V
     while let Some(row) = rows.next()? {
         // Converts database string rfc time to DateTime object
         let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
-        let file_row: FileRow = utils::convert_to_file_row(
+        let mut file_row: FileRow = utils::convert_to_file_row(
             row.get(0)?,
             row.get(1)?,
             last_modified.to_utc()
         );
+        file_row.set_base_hash(row.get(3)?);
+        file_row.set_mode(row.get(4)?);
+        file_row.set_symlink(row.get(5)?);
+        file_row.set_size(row.get(6)?);
+        file_row.set_inode(row.get(7)?);
 
         file_rows.push(file_row);
     }
@@ -72,22 +163,311 @@ pub fn remove_file(conn: &Connection, path: &String, root_dir: &String) -> Resul
     Ok(())
 }
 
+// Files inside a directory, at any depth
+pub fn get_files_under(conn: &Connection, path: &str, root_dir: &String) -> Result<Vec<FileRow>, DbError> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let mut statement = conn.prepare(
+        "SELECT path, hash, last_modified, base_hash, mode, symlink, size, inode FROM files WHERE substr(path, 1, length(?1))=?1 AND root_dir=?2"
+    )?;
+
+    let mut rows = statement.query(params![prefix, root_dir])?;
+    let mut files: Vec<FileRow> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
+        let mut file_row = utils::convert_to_file_row(
+            row.get(0)?,
+            row.get(1)?,
+            last_modified.to_utc()
+        );
+        file_row.set_base_hash(row.get(3)?);
+        file_row.set_mode(row.get(4)?);
+        file_row.set_symlink(row.get(5)?);
+        file_row.set_size(row.get(6)?);
+        file_row.set_inode(row.get(7)?);
+        files.push(file_row);
+    }
+
+    Ok(files)
+}
+
+pub fn move_file(conn: &Connection, from: &String, to: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE files SET path=?1 WHERE path=?2 AND root_dir=?3"
+    )?;
+
+    statement.execute(params![to, from, root_dir])?;
+    Ok(())
+}
+
+// Returns whether the directory wasn't tracked already
+pub fn insert_directory(conn: &Connection, path: &String, root_dir: &String) -> Result<bool, DbError> {
+    let mut statement = conn.prepare(
+        "INSERT OR IGNORE INTO directories(path, root_dir) VALUES (?1, ?2)"
+    )?;
+
+    Ok(statement.execute(params![path, root_dir])? > 0)
+}
+
+pub fn directory_exists(conn: &Connection, path: &String, root_dir: &String) -> Result<bool, DbError> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM directories WHERE path=?1 AND root_dir=?2",
+        params![path, root_dir],
+        |row| row.get(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+// Deepest first, so they can be removed in order
+pub fn get_directories(conn: &Connection, root_dir: &String) -> Result<Vec<String>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT path FROM directories WHERE root_dir=?1 ORDER BY length(path) DESC"
+    )?;
+
+    let paths = statement
+        .query_map(params![root_dir], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(paths)
+}
+
+// A directory and every directory inside it, deepest first
+pub fn get_directories_under(conn: &Connection, path: &str, root_dir: &String) -> Result<Vec<String>, DbError> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let mut statement = conn.prepare(
+        "SELECT path FROM directories WHERE (path=?1 OR substr(path, 1, length(?2))=?2) AND root_dir=?3 ORDER BY length(path) DESC"
+    )?;
+
+    let paths = statement
+        .query_map(params![path, prefix, root_dir], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(paths)
+}
+
+pub fn remove_directory(conn: &Connection, path: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "DELETE FROM directories WHERE path=?1 AND root_dir=?2"
+    )?;
+
+    statement.execute(params![path, root_dir])?;
+    Ok(())
+}
+
+pub fn move_directory(conn: &Connection, from: &String, to: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE directories SET path=?1 WHERE path=?2 AND root_dir=?3"
+    )?;
+
+    statement.execute(params![to, from, root_dir])?;
+    Ok(())
+}
+
 pub fn get_files(conn: &Connection, root_dir: &String) -> Result<Vec<FileRow>, DbError> {
-    let mut statement = conn.prepare("SELECT path, hash, last_modified FROM files WHERE root_dir=?1")?;
+    let mut statement = conn.prepare("SELECT path, hash, last_modified, base_hash, mode, symlink, size, inode FROM files WHERE root_dir=?1")?;
 
     let mut rows = statement.query(params![root_dir])?;
     let mut files: Vec<FileRow> = Vec::new();
 
     while let Some(row) = rows.next()? {
         let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
-        let file_row = utils::convert_to_file_row(
+        let mut file_row = utils::convert_to_file_row(
             row.get(0)?,
             row.get(1)?,
             last_modified.to_utc()
         );
+        file_row.set_base_hash(row.get(3)?);
+        file_row.set_mode(row.get(4)?);
+        file_row.set_symlink(row.get(5)?);
+        file_row.set_size(row.get(6)?);
+        file_row.set_inode(row.get(7)?);
         files.push(file_row);
     };
 
     Ok(files)
 
-}
\ No newline at end of file
+}
+
+// Record an upload or delete for a path, replacing whatever was queued for it before
+pub fn queue_op(conn: &Connection, path: &String, kind: OpKind, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO pending_ops(path, root_dir, op, attempts, next_attempt)\
+            VALUES (?1, ?2, ?3, 0, ?4)\
+            ON CONFLICT(path, root_dir) DO UPDATE SET op=excluded.op, attempts=0, next_attempt=excluded.next_attempt, last_error=NULL"
+    )?;
+
+    statement.execute(params![path, root_dir, kind.as_str(), Utc::now().to_rfc3339()])?;
+    Ok(())
+}
+
+pub fn get_pending_ops(conn: &Connection, root_dir: &String) -> Result<Vec<PendingOp>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT path, op, attempts FROM pending_ops WHERE root_dir=?1 ORDER BY id"
+    )?;
+
+    let mut rows = statement.query(params![root_dir])?;
+    let mut ops: Vec<PendingOp> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        ops.push(convert_to_pending_op(row)?);
+    }
+
+    Ok(ops)
+}
+
+pub fn get_due_ops(conn: &Connection, root_dir: &String, now: DateTime<Utc>) -> Result<Vec<PendingOp>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT path, op, attempts FROM pending_ops WHERE root_dir=?1 AND next_attempt<=?2 ORDER BY id"
+    )?;
+
+    let mut rows = statement.query(params![root_dir, now.to_rfc3339()])?;
+    let mut ops: Vec<PendingOp> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        ops.push(convert_to_pending_op(row)?);
+    }
+
+    Ok(ops)
+}
+
+// Drop whatever is queued for files inside a directory
+pub fn remove_ops_under(conn: &Connection, path: &str, root_dir: &String) -> Result<(), DbError> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let mut statement = conn.prepare(
+        "DELETE FROM pending_ops WHERE substr(path, 1, length(?1))=?1 AND root_dir=?2"
+    )?;
+
+    statement.execute(params![prefix, root_dir])?;
+    Ok(())
+}
+
+// Push a failed op back until next_attempt
+pub fn reschedule_op(conn: &Connection, path: &String, next_attempt: DateTime<Utc>, error: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE pending_ops SET attempts=attempts+1, next_attempt=?1, last_error=?2 WHERE path=?3 AND root_dir=?4"
+    )?;
+
+    statement.execute(params![next_attempt.to_rfc3339(), error, path, root_dir])?;
+    Ok(())
+}
+
+// Make every queued op due straight away
+pub fn retry_ops_now(conn: &Connection, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE pending_ops SET next_attempt=?1 WHERE root_dir=?2"
+    )?;
+
+    statement.execute(params![Utc::now().to_rfc3339(), root_dir])?;
+    Ok(())
+}
+
+pub fn remove_op(conn: &Connection, path: &String, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "DELETE FROM pending_ops WHERE path=?1 AND root_dir=?2"
+    )?;
+
+    statement.execute(params![path, root_dir])?;
+    Ok(())
+}
+
+pub fn get_cursor(conn: &Connection, root_dir: &String) -> Result<Option<i64>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT cursor FROM sync_cursors WHERE root_dir=?1"
+    )?;
+
+    let mut rows = statement.query(params![root_dir])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn set_cursor(conn: &Connection, cursor: i64, root_dir: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO sync_cursors(root_dir, cursor) VALUES (?1, ?2)\
+            ON CONFLICT(root_dir) DO UPDATE SET cursor=excluded.cursor"
+    )?;
+
+    statement.execute(params![root_dir, cursor])?;
+    Ok(())
+}
+
+// Forget every directory's cursor, so the next sync compares against the full server listing
+pub fn clear_cursors(conn: &Connection) -> Result<(), DbError> {
+    conn.execute("DELETE FROM sync_cursors", params![])?;
+    Ok(())
+}
+
+pub fn get_selective_rules(conn: &Connection) -> Result<Vec<SelectiveRule>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT path, mode FROM selective_sync ORDER BY path"
+    )?;
+
+    let mut rows = statement.query(params![])?;
+    let mut rules: Vec<SelectiveRule> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let mode = match SelectiveMode::parse(&row.get::<_, String>(1)?) {
+            Some(mode) => mode,
+            None => return Err(DbError::Custom(String::from("Unknown selective sync mode")))
+        };
+
+        rules.push(SelectiveRule::new(row.get(0)?, mode));
+    }
+
+    Ok(rules)
+}
+
+pub fn set_selective_rule(conn: &Connection, path: &String, mode: SelectiveMode) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO selective_sync(path, mode) VALUES (?1, ?2)\
+            ON CONFLICT(path) DO UPDATE SET mode=excluded.mode"
+    )?;
+
+    statement.execute(params![path, mode.as_str()])?;
+    bump_selective_version(conn)
+}
+
+// Returns whether there was a rule for the path
+pub fn remove_selective_rule(conn: &Connection, path: &String) -> Result<bool, DbError> {
+    let mut statement = conn.prepare(
+        "DELETE FROM selective_sync WHERE path=?1"
+    )?;
+
+    let removed = statement.execute(params![path])? > 0;
+    if removed {
+        bump_selective_version(conn)?;
+    }
+
+    Ok(removed)
+}
+
+pub fn get_selective_version(conn: &Connection) -> Result<i64, DbError> {
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM selective_sync_version",
+        params![],
+        |row| row.get(0),
+    )?;
+
+    Ok(version)
+}
+
+fn bump_selective_version(conn: &Connection) -> Result<(), DbError> {
+    conn.execute(
+        "INSERT INTO selective_sync_version(id, version) VALUES (1, 1)\
+            ON CONFLICT(id) DO UPDATE SET version=version + 1",
+        params![],
+    )?;
+
+    Ok(())
+}
+
+fn convert_to_pending_op(row: &rusqlite::Row) -> Result<PendingOp, DbError> {
+    let kind = match OpKind::parse(&row.get::<_, String>(1)?) {
+        Some(kind) => kind,
+        None => return Err(DbError::Custom(String::from("Unknown pending op")))
+    };
+
+    Ok(PendingOp::new(row.get(0)?, kind, row.get(2)?))
+}
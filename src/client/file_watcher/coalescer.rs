@@ -0,0 +1,405 @@
+// Collects file events per path until the path goes quiet and stops changing on disk, then hands back one net operation for it
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use notify::{Event, EventKind};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+
+// How long a path has to go without events before its net operation is emitted
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+// Delay before the first retry of a file that couldn't be read, doubling with each attempt after
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// How many times a file that couldn't be read is retried before it is left for the next sync
+const MAX_ATTEMPTS: u32 = 5;
+
+// How long the first half of a rename waits for its second before the path is taken to have left the tree
+const RENAME_PAIR_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetOp {
+    // Created or modified
+    Changed,
+    Removed,
+    // Moved here from another path in the tree, possibly changed since
+    Renamed(PathBuf),
+}
+
+// A file's size and modified time, which stop moving once whatever is writing it is done
+type Snapshot = Option<(u64, Option<SystemTime>)>;
+
+struct Pending {
+    op: NetOp,
+    last_seen: Instant,
+    snapshot: Snapshot,
+    // The writer closed the file, so it is complete without waiting to see it settle
+    closed: bool,
+    // First seen being created, so if it is gone again there was never anything to sync
+    created: bool,
+    attempts: u32,
+}
+
+impl Pending {
+    fn new(op: NetOp, path: &Path) -> Self {
+        Pending {
+            op,
+            last_seen: Instant::now(),
+            snapshot: snapshot(path),
+            closed: false,
+            created: false,
+            attempts: 0,
+        }
+    }
+}
+
+pub struct Coalescer {
+    pending: HashMap<PathBuf, Pending>,
+    // Renamed away paths waiting to be paired with where they went, by rename tracker
+    renames: HashMap<usize, (PathBuf, Instant)>,
+    // A directory was moved in from outside the tree or events were dropped, so everything needs syncing
+    rescan: bool,
+    // Earlier failed attempts at the paths in the last batch, by path
+    attempts: HashMap<PathBuf, u32>,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Coalescer {
+            pending: HashMap::new(),
+            renames: HashMap::new(),
+            rescan: false,
+            attempts: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, mut event: Event) {
+        // The OS queue overflowed or the backend lost track, so events may be missing
+        if event.need_rescan() {
+            self.rescan = true;
+            return;
+        }
+
+        match event.kind {
+            // A rename shows up as a From half and a To half, then a Both event pairing them
+            EventKind::Modify(ModifyKind::Name(mode)) => match (mode, event.tracker()) {
+                (RenameMode::From, Some(tracker)) => {
+                    if let Some(path) = event.paths.pop() {
+                        self.renames.insert(tracker, (path, Instant::now()));
+                    }
+                }
+                (RenameMode::To, Some(tracker)) if self.renames.contains_key(&tracker) => {}
+                // Moved in from outside the tree
+                (RenameMode::To, _) if event.paths.first().is_some_and(|path| path.is_dir()) => self.rescan = true,
+                (RenameMode::Both, tracker) => {
+                    if let Some(tracker) = tracker {
+                        self.renames.remove(&tracker);
+                    }
+
+                    if let [from, to] = &event.paths[..] {
+                        self.rename(from.clone(), to.clone());
+                    }
+                }
+                _ => {
+                    for path in event.paths {
+                        self.record(path, NetOp::Changed);
+                    }
+                }
+            },
+            EventKind::Create(_) => {
+                for path in event.paths {
+                    let created = !self.pending.contains_key(&path);
+                    self.record(path.clone(), NetOp::Changed);
+                    if let Some(pending) = self.pending.get_mut(&path) {
+                        pending.created |= created;
+                    }
+                }
+            }
+            EventKind::Modify(_) | EventKind::Any => {
+                for path in event.paths {
+                    self.record(path, NetOp::Changed);
+                }
+            }
+            // Only reported on some platforms, but lets a finished file through without waiting on it
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in event.paths {
+                    self.record(path.clone(), NetOp::Changed);
+                    if let Some(pending) = self.pending.get_mut(&path) {
+                        pending.closed = true;
+                    }
+                }
+            }
+            EventKind::Remove(_) => {
+                for path in event.paths {
+                    self.record(path, NetOp::Removed);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Whether the tree needs syncing as a whole since the last call
+    pub fn take_rescan(&mut self) -> bool {
+        std::mem::take(&mut self.rescan)
+    }
+
+    // A file from the last batch couldn't be read, so try it again after a growing delay,
+    // returning false once it has failed too many times
+    pub fn retry(&mut self, path: PathBuf) -> bool {
+        let attempts = self.attempts.remove(&path).unwrap_or(0) + 1;
+        if attempts > MAX_ATTEMPTS {
+            return false;
+        }
+
+        let retry_at = Instant::now() + RETRY_DELAY * 2u32.pow(attempts - 1);
+        let pending = self.pending.entry(path.clone()).or_insert_with(|| Pending::new(NetOp::Changed, &path));
+        pending.attempts = attempts;
+        pending.last_seen = pending.last_seen.max(retry_at);
+        true
+    }
+
+    // When the next path could be ready, if anything is waiting
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let pending = self.pending.values().map(|pending| if pending.closed { pending.last_seen } else { pending.last_seen + QUIET_PERIOD });
+        let renames = self.renames.values().map(|(_, at)| *at + RENAME_PAIR_TIMEOUT);
+        pending.chain(renames).min()
+    }
+
+    // Take every path that has gone quiet and stopped changing, parents ahead of what is inside them
+    pub fn ready(&mut self) -> Vec<(PathBuf, NetOp)> {
+        let now = Instant::now();
+        self.attempts.clear();
+
+        // Renamed out of the tree
+        let expired = self.renames
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) > RENAME_PAIR_TIMEOUT)
+            .map(|(tracker, _)| *tracker)
+            .collect::<Vec<usize>>();
+
+        for tracker in expired {
+            if let Some((path, _)) = self.renames.remove(&tracker) {
+                self.record(path, NetOp::Removed);
+            }
+        }
+
+        let mut quiet = self.pending
+            .iter()
+            .filter(|(_, pending)| {
+                now >= pending.last_seen && (pending.closed || now.duration_since(pending.last_seen) >= QUIET_PERIOD)
+            })
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<PathBuf>>();
+        quiet.sort();
+
+        let mut settled = Vec::new();
+        for path in quiet {
+            let Some(mut pending) = self.pending.remove(&path) else {
+                continue;
+            };
+
+            // Still being written without telling us, so look again once it has had time to settle
+            let current = snapshot(&path);
+            if !pending.closed && current != pending.snapshot {
+                pending.snapshot = current;
+                pending.last_seen = now;
+                self.pending.insert(path, pending);
+                continue;
+            }
+
+            if pending.created && fs::symlink_metadata(&path).is_err() {
+                continue;
+            }
+
+            if pending.attempts > 0 {
+                self.attempts.insert(path.clone(), pending.attempts);
+            }
+
+            settled.push(resolve(path, pending.op));
+        }
+
+        settled
+    }
+
+    fn record(&mut self, path: PathBuf, op: NetOp) {
+        let current = snapshot(&path);
+        let pending = self.pending.entry(path).or_insert_with_key(|path| Pending::new(op.clone(), path));
+        pending.last_seen = pending.last_seen.max(Instant::now());
+        pending.snapshot = current;
+        pending.closed = false;
+
+        // A renamed file removed again takes the old path with it, and one edited after the rename still needs moving first
+        let orphan = match (&pending.op, &op) {
+            (NetOp::Renamed(from), NetOp::Removed) => Some(from.clone()),
+            _ => None,
+        };
+
+        if !(op == NetOp::Changed && matches!(pending.op, NetOp::Renamed(_))) {
+            pending.op = op;
+        }
+
+        if let Some(from) = orphan {
+            self.pending.entry(from).or_insert_with_key(|from| Pending::new(NetOp::Removed, from));
+        }
+    }
+
+    fn rename(&mut self, from: PathBuf, to: PathBuf) {
+        // Keep following a path renamed more than once, back to where it was last synced
+        let op = match self.pending.remove(&from) {
+            Some(Pending { op: NetOp::Renamed(origin), .. }) => NetOp::Renamed(origin),
+            _ => NetOp::Renamed(from.clone()),
+        };
+
+        // Anything still waiting inside a renamed directory moved with it
+        let inside = self.pending
+            .keys()
+            .filter(|path| path.starts_with(&from) && **path != from)
+            .cloned()
+            .collect::<Vec<PathBuf>>();
+
+        for path in inside {
+            if let (Some(pending), Ok(rest)) = (self.pending.remove(&path), path.strip_prefix(&from)) {
+                self.pending.insert(to.join(rest), pending);
+            }
+        }
+
+        self.record(to, op);
+    }
+}
+
+fn snapshot(path: &Path) -> Snapshot {
+    fs::symlink_metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
+// Whatever happened to a path along the way, what is on disk now decides its net operation
+fn resolve(path: PathBuf, op: NetOp) -> (PathBuf, NetOp) {
+    let exists = fs::symlink_metadata(&path).is_ok();
+    match op {
+        // Renamed and then gone again, so only the old path is left to delete
+        NetOp::Renamed(from) if !exists => (from, NetOp::Removed),
+        _ if !exists => (path, NetOp::Removed),
+        NetOp::Removed => (path, NetOp::Changed),
+        op => (path, op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustysync-coalescer-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| event.add_path(path.to_path_buf()))
+    }
+
+    // Make everything waiting look like it last changed a quiet period ago
+    fn wait_out(queue: &mut Coalescer) {
+        let quiet = Instant::now() - QUIET_PERIOD;
+        for pending in queue.pending.values_mut() {
+            pending.last_seen = quiet;
+        }
+    }
+
+    #[test]
+    fn create_then_modify_is_changed() {
+        let dir = temp_dir();
+        let path = dir.join("new.txt");
+        let mut queue = Coalescer::new();
+
+        fs::write(&path, "a").unwrap();
+        queue.push(event(EventKind::Create(CreateKind::File), &[&path]));
+        fs::write(&path, "ab").unwrap();
+        queue.push(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &[&path]));
+        wait_out(&mut queue);
+
+        assert_eq!(queue.ready(), vec![(path, NetOp::Changed)]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn create_then_remove_is_nothing() {
+        let dir = temp_dir();
+        let path = dir.join("scratch.txt");
+        let mut queue = Coalescer::new();
+
+        fs::write(&path, "a").unwrap();
+        queue.push(event(EventKind::Create(CreateKind::File), &[&path]));
+        fs::remove_file(&path).unwrap();
+        queue.push(event(EventKind::Remove(RemoveKind::File), &[&path]));
+        wait_out(&mut queue);
+
+        assert!(queue.ready().is_empty());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rename_halves_pair_into_renamed() {
+        let dir = temp_dir();
+        let (from, to) = (dir.join("old.txt"), dir.join("new.txt"));
+        let mut queue = Coalescer::new();
+
+        fs::write(&from, "a").unwrap();
+        fs::rename(&from, &to).unwrap();
+        queue.push(event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &[&from]).set_tracker(1));
+        queue.push(event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &[&to]).set_tracker(1));
+        queue.push(event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &[&from, &to]).set_tracker(1));
+        wait_out(&mut queue);
+
+        assert_eq!(queue.ready(), vec![(to, NetOp::Renamed(from))]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn ready_holds_paths_until_quiet() {
+        let dir = temp_dir();
+        let path = dir.join("busy.txt");
+        let mut queue = Coalescer::new();
+
+        fs::write(&path, "a").unwrap();
+        queue.push(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &[&path]));
+
+        assert!(queue.ready().is_empty());
+        assert!(queue.next_deadline().is_some());
+
+        wait_out(&mut queue);
+        assert_eq!(queue.ready(), vec![(path, NetOp::Changed)]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn retry_gives_up_after_max_attempts() {
+        let dir = temp_dir();
+        let path = dir.join("locked.txt");
+        let mut queue = Coalescer::new();
+
+        fs::write(&path, "a").unwrap();
+        queue.push(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &[&path]));
+
+        for _ in 0..MAX_ATTEMPTS {
+            wait_out(&mut queue);
+            assert_eq!(queue.ready(), vec![(path.clone(), NetOp::Changed)]);
+            assert!(queue.retry(path.clone()));
+        }
+
+        wait_out(&mut queue);
+        assert_eq!(queue.ready(), vec![(path.clone(), NetOp::Changed)]);
+        assert!(!queue.retry(path));
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,152 @@
+// Paths excluded from syncing by .rustysyncignore files, using gitignore rules
+use std::fs;
+use std::path::{Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+pub const IGNORE_FILE_NAME: &str = ".rustysyncignore";
+
+pub struct IgnoreRules {
+    // Each ignore file's directory and rules, deepest last
+    matchers: Vec<(PathBuf, Gitignore)>,
+}
+
+impl IgnoreRules {
+    // Read the ignore files at the watch root and in every directory below it that isn't itself ignored
+    pub fn load(root: &Path) -> Self {
+        let mut rules = IgnoreRules { matchers: Vec::new() };
+        rules.load_dir(root);
+        rules
+    }
+
+    fn load_dir(&mut self, dir: &Path) {
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        if ignore_file.is_file() {
+            let mut builder = GitignoreBuilder::new(dir);
+            if let Some(e) = builder.add(&ignore_file) {
+                eprintln!("Error reading {}. {}", ignore_file.display(), e);
+            }
+
+            match builder.build() {
+                Ok(gitignore) => self.matchers.push((dir.to_path_buf(), gitignore)),
+                Err(e) => eprintln!("Error reading {}. {}", ignore_file.display(), e),
+            }
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Error reading directory {}. {}", dir.display(), e);
+                return;
+            }
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) && !self.is_ignored(&path, true) {
+                self.load_dir(&path);
+            }
+        }
+    }
+
+    // Whether a path under the watch root is excluded, the closest ignore file with a matching rule decides
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for (dir, gitignore) in self.matchers.iter().rev() {
+            if !path.starts_with(dir) {
+                continue;
+            }
+
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        false
+    }
+}
+
+// Whether a path is one of the ignore files themselves
+pub fn is_ignore_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == IGNORE_FILE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    // A temp watch root holding the given ignore files, keyed by the directory they sit in
+    fn watch_root(ignore_files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rustysync-ignore-{}", Uuid::new_v4()));
+
+        for (dir, rules) in ignore_files {
+            let dir = root.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(IGNORE_FILE_NAME), rules).unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn child_ignore_file_overrides_parent() {
+        let root = watch_root(&[("", "*.log\n"), ("keep", "!*.log\n")]);
+        let rules = IgnoreRules::load(&root);
+
+        assert!(rules.is_ignored(&root.join("debug.log"), false));
+        assert!(rules.is_ignored(&root.join("other/debug.log"), false));
+        assert!(!rules.is_ignored(&root.join("keep/debug.log"), false));
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn negation_within_one_file() {
+        let root = watch_root(&[("", "*.tmp\n!important.tmp\n")]);
+        let rules = IgnoreRules::load(&root);
+
+        assert!(rules.is_ignored(&root.join("scratch.tmp"), false));
+        assert!(!rules.is_ignored(&root.join("important.tmp"), false));
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn rules_only_apply_below_their_directory() {
+        let root = watch_root(&[("a", "*.log\n"), ("b", "")]);
+        let rules = IgnoreRules::load(&root);
+
+        assert!(rules.is_ignored(&root.join("a/debug.log"), false));
+        assert!(rules.is_ignored(&root.join("a/nested/debug.log"), false));
+        assert!(!rules.is_ignored(&root.join("b/debug.log"), false));
+        assert!(!rules.is_ignored(&root.join("debug.log"), false));
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn directory_only_patterns() {
+        let root = watch_root(&[("", "build/\n")]);
+        let rules = IgnoreRules::load(&root);
+
+        // The directory and everything in it, but not a file of the same name
+        assert!(rules.is_ignored(&root.join("build"), true));
+        assert!(rules.is_ignored(&root.join("build/out.bin"), false));
+        assert!(rules.is_ignored(&root.join("src/build/out.bin"), false));
+        assert!(!rules.is_ignored(&root.join("build"), false));
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn ignored_directories_are_not_searched_for_ignore_files() {
+        let root = watch_root(&[("", "vendor/\n"), ("vendor", "!*\n")]);
+        let rules = IgnoreRules::load(&root);
+
+        assert!(rules.is_ignored(&root.join("vendor/lib.rs"), false));
+
+        fs::remove_dir_all(root).ok();
+    }
+}
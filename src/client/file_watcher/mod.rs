@@ -1,2 +1,6 @@
 pub mod watcher;
-pub mod sync;
\ No newline at end of file
+pub mod sync;
+pub mod subscriber;
+pub mod ignore_rules;
+pub mod selective_sync;
+pub mod coalescer;
\ No newline at end of file
@@ -0,0 +1,72 @@
+// Remote subtrees the user has chosen not to keep locally, from the client DB
+use rusqlite::Connection;
+use crate::client::db;
+use crate::shared::models::{SelectiveMode, SelectiveRule};
+
+#[derive(Debug, PartialEq)]
+pub struct SelectiveSync {
+    rules: Vec<SelectiveRule>,
+}
+
+impl SelectiveSync {
+    pub fn load(conn: &Connection) -> Self {
+        let rules = db::get_selective_rules(conn).unwrap_or_else(|e| {
+            eprintln!("Error getting selective sync rules. {}", e);
+            Vec::new()
+        });
+
+        SelectiveSync { rules }
+    }
+
+    // Changes whenever a rule is set or removed, cheaper to poll than the rules themselves
+    pub fn version(conn: &Connection) -> i64 {
+        db::get_selective_version(conn).unwrap_or_else(|e| {
+            eprintln!("Error getting selective sync version. {}", e);
+            0
+        })
+    }
+
+    // Whether a synced path (as stored in the DB and on the server) sits in an excluded subtree,
+    // the longest rule covering it decides and anything no rule covers is synced
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| covers(rule.path(), path))
+            .max_by_key(|rule| rule.path().len())
+            .is_some_and(|rule| rule.mode() == SelectiveMode::Exclude)
+    }
+}
+
+fn covers(rule_path: &str, path: &str) -> bool {
+    path.strip_prefix(rule_path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selective(rules: &[(&str, SelectiveMode)]) -> SelectiveSync {
+        SelectiveSync {
+            rules: rules.iter().map(|(path, mode)| SelectiveRule::new(path.to_string(), *mode)).collect(),
+        }
+    }
+
+    #[test]
+    fn longest_rule_wins() {
+        let selective = selective(&[("a", SelectiveMode::Exclude), ("a/b", SelectiveMode::Include)]);
+
+        assert!(!selective.is_excluded("a/b/c"));
+        assert!(!selective.is_excluded("a/b"));
+        assert!(selective.is_excluded("a/c"));
+        assert!(selective.is_excluded("a"));
+    }
+
+    #[test]
+    fn rules_only_cover_whole_components() {
+        let selective = selective(&[("a", SelectiveMode::Exclude)]);
+
+        assert!(!selective.is_excluded("ab"));
+        assert!(!selective.is_excluded("b/a"));
+        assert!(!selective.is_excluded("c"));
+    }
+}
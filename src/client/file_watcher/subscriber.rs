@@ -0,0 +1,85 @@
+// Listens to the server's event stream so changes from other devices are pulled straight away
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use futures_util::StreamExt;
+use crate::client::apis;
+use crate::shared::models::{FileEvent, FileEventKind};
+
+// Backoff between attempts to reconnect to the event stream
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+// Forward server events to the watcher until it stops listening
+pub fn spawn(sender: Sender<FileEvent>) {
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_BASE_DELAY;
+
+        loop {
+            let response = apis::file::open_event_stream().await.map_err(|e| e.to_string());
+
+            match response {
+                Ok(response) => {
+                    println!("Subscribed to server events");
+                    delay = RECONNECT_BASE_DELAY;
+
+                    // Anything that changed before subscribing never came through as an event
+                    if sender.send(resync_event()).is_err() {
+                        return;
+                    }
+
+                    if !forward_events(response, &sender).await {
+                        return;
+                    }
+                    eprintln!("Event stream closed, reconnecting");
+                }
+                Err(e) => eprintln!("Error subscribing to server events. {}", e),
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    });
+}
+
+// Parse Server-Sent Events off the response, returning false once the watcher has gone away
+async fn forward_events(response: reqwest::Response, sender: &Sender<FileEvent>) -> bool {
+    let mut stream = response.bytes_stream();
+    let mut buffer = String::new();
+
+    while let Some(Ok(bytes)) = stream.next().await {
+        buffer.push_str(&String::from_utf8_lossy(&bytes).replace("\r\n", "\n"));
+
+        // A blank line ends each message
+        while let Some(end) = buffer.find("\n\n") {
+            let message = buffer[..end].to_string();
+            buffer.drain(..end + 2);
+
+            let data = message
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|line| line.trim_start())
+                .collect::<Vec<&str>>()
+                .join("\n");
+
+            // Comment lines are only keep-alives
+            if data.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<FileEvent>(&data) {
+                Ok(event) => {
+                    if sender.send(event).is_err() {
+                        return false;
+                    }
+                }
+                Err(e) => eprintln!("Error parsing server event. {}", e),
+            }
+        }
+    }
+
+    true
+}
+
+fn resync_event() -> FileEvent {
+    FileEvent::new(FileEventKind::Resync, String::new(), None)
+}
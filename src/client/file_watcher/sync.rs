@@ -1,51 +1,70 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf, Component::ParentDir};
 use rusqlite::Connection;
 // This module can walk entire directories recursively and efficently
 use walkdir::WalkDir;
+use rayon::prelude::*;
 use chrono::{DateTime, Utc};
 use crate::shared::utils;
 use crate::client::{ db, apis };
-use crate::shared::models::FileRow;
+use crate::client::file_watcher::ignore_rules::IgnoreRules;
+use crate::client::file_watcher::selective_sync::SelectiveSync;
+use crate::shared::models::{ConflictPolicy, FileEvent, FileEventKind, FileRow, OpKind, SymlinkPolicy};
+use chrono::TimeDelta;
+use tokio::fs;
 
-pub async fn sync(root: &PathBuf, conn: &Connection, init_dir: &PathBuf) {
+// Backoff for changes the server hasn't acknowledged
+const RETRY_BASE_DELAY: TimeDelta = TimeDelta::seconds(5);
+const RETRY_MAX_DELAY: TimeDelta = TimeDelta::minutes(15);
+
+pub async fn sync(root: &PathBuf, conn: &Connection, init_dir: &Path, policy: ConflictPolicy, symlinks: SymlinkPolicy) {
     let mut file_paths: HashMap<String, u8> = HashMap::new();
+    let mut directory_paths: HashSet<String> = HashSet::new();
+    // Paths of untracked files by hash, to spot files that were moved while we weren't watching
+    let mut new_files: HashMap<String, Vec<String>> = HashMap::new();
     let root_dir = init_dir.to_string_lossy().to_string();
-    let mut all_files: Vec<FileRow> = Vec::new();
-    // Loop through files
+    let rules = IgnoreRules::load(root);
+    let selective = SelectiveSync::load(conn);
+    // Files that changed or are new, read all at once after the walk
+    let mut to_hash = Vec::new();
+    // Loop through files, skipping ignored and excluded directories entirely.
+    // Links are only followed when the policy says so, walkdir reports any loop they make as an error
     for entry in WalkDir::new(root)
+        .follow_links(symlinks == SymlinkPolicy::FollowWithinRoot)
         .into_iter()
+        .filter_entry(|x| {
+            !rules.is_ignored(x.path(), x.file_type().is_dir())
+                && !tracked_path(x.path(), root, init_dir).is_some_and(|p| selective.is_excluded(&p))
+                && (!x.path_is_symlink() || !link_excluded(symlinks, x.path(), root))
+        })
         .filter_map(|x| x.ok())
-        .filter(|x| x.file_type().is_file())
     {
-        let mut root_path = PathBuf::from(init_dir);
-        let path = entry.path().to_path_buf();
-        if !utils::check_file_path(&path) {
+        let as_link = entry.path_is_symlink() && symlinks == SymlinkPolicy::Preserve;
+
+        // Directories are tracked on their own so empty ones sync too
+        if entry.file_type().is_dir() {
+            if entry.depth() > 0 && let Some(directory) = tracked_path(entry.path(), root, init_dir) {
+                track_directory(conn, &directory, &root_dir);
+                directory_paths.insert(directory);
+            }
             continue;
         }
-        let file = File::open(&path).unwrap();
 
-        let hash = match utils::hash_file(&file) {
-            Some(hash) => hash,
-            None => {
-                eprintln!("Error hashing file");
-                continue;
-            }
-        };
+        if !as_link && !entry.file_type().is_file() {
+            continue;
+        }
 
-        let last_modified = file.metadata().unwrap().modified().unwrap();
+        let path = entry.path().to_path_buf();
+        if !as_link && !utils::check_file_path(&path) {
+            continue;
+        }
 
-        let last_modified_utc = DateTime::<Utc>::from(last_modified);
-        let relative_path = match path.strip_prefix(&root) {
-            Ok(p) => p.to_path_buf(),
-            Err(_) => {
-                eprintln!("Failed to get relative path for {:?}", path);
-                continue;
-            }
+        let Some(file_path) = tracked_path(&path, root, init_dir) else {
+            eprintln!("Failed to get relative path for {:?}", path);
+            continue;
         };
-        root_path.push(&relative_path);
-        let file_path = utils::format_file_path(&root_path.to_string_lossy().to_string());
         let query = db::get_file(conn, &file_path, &root_dir);
 
         let file_rows = match query {
@@ -56,45 +75,72 @@ pub async fn sync(root: &PathBuf, conn: &Connection, init_dir: &PathBuf) {
             }
         };
 
-        if file_rows.len() > 0 {
-            let mut file_row = file_rows.get(0).unwrap().clone();
-            if file_row.hash() == hash {
-                file_paths.insert(file_path, 1);
-                continue;
-            }
+        // Nothing about it moved since it was last hashed, so it isn't read again
+        if file_rows.first().is_some_and(|file_row| matches_disk(file_row, &path, as_link)) {
+            file_paths.insert(file_path, 1);
+            continue;
+        }
+
+        to_hash.push((path, file_path, as_link, file_rows.into_iter().next()));
+    }
+
+    // Hash whatever is left across the thread pool, the DB is only touched back here
+    let hashed = to_hash
+        .into_par_iter()
+        .map(|(path, file_path, as_link, file_row)| {
+            let entry = read_entry(&path, as_link);
+            (path, file_path, as_link, file_row, entry)
+        })
+        .collect::<Vec<_>>();
+
+    for (path, file_path, as_link, file_row, entry) in hashed {
+        // Couldn't be read or is still being written, it's still there so it isn't treated as deleted
+        let Some(entry) = entry else {
+            eprintln!("Error hashing file {}", path.display());
+            file_paths.insert(file_path, 1);
+            continue;
+        };
+
+        if let Some(mut file_row) = file_row {
+            if file_row.hash() == entry.hash && file_row.mode() == entry.mode && file_row.is_symlink() == as_link {
+                // Same contents, remember how it looks on disk so the next scan can skip it
+                if note_disk(&mut file_row, &entry) {
+                    db::update_file(conn, &file_row, &root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+                }
 
-            if file_row.last_modified() == last_modified_utc {
                 file_paths.insert(file_path, 1);
                 continue;
             }
 
-            file_row.set_last_modified(last_modified_utc);
-            file_row.set_hash(hash);
-
-            all_files.push(file_row.clone());
+            record_mode(&mut file_row, &entry.hash, entry.mode);
+            file_row.set_symlink(as_link);
+            note_disk(&mut file_row, &entry);
+            file_row.set_hash(entry.hash);
 
             println!("Syncing file {}", path.display());
             db::update_file(conn, &file_row, &root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+            queue_op(conn, &file_row.path().to_string(), OpKind::Upload, &root_dir);
         } else {
             // This file doesnt exist, lets create an entry
 
-            let new_file_row = utils::convert_to_file_row(
+            new_files.entry(entry.hash.clone()).or_default().push(file_path.clone());
+            let mut new_file_row = utils::convert_to_file_row(
                 file_path.clone(),
-                hash,
-                last_modified_utc
+                entry.hash.clone(),
+                entry.last_modified
             );
+            new_file_row.set_mode(entry.mode);
+            new_file_row.set_symlink(as_link);
+            note_disk(&mut new_file_row, &entry);
 
-            all_files.push(new_file_row.clone());
-
-            println!("New file {}", root_path.display());
+            println!("New file {}", file_path);
             db::insert_file(conn, &new_file_row, &root_dir).unwrap_or_else(|e| {
                 eprintln!("Failed to insert new: {:?}", e);
-            })
+            });
+            queue_op(conn, &file_path, OpKind::Upload, &root_dir);
         }
 
         file_paths.insert(file_path, 1);
-        root_path.clear();
-
     }
 
     // go through db and clean deleted files
@@ -103,39 +149,1023 @@ pub async fn sync(root: &PathBuf, conn: &Connection, init_dir: &PathBuf) {
         Vec::new()
     });
 
-    let mut delete_paths: Vec<String> = Vec::new();
-
     if file_rows.len() > 0 {
         for file_row in file_rows.iter() {
             if let None = file_paths.get(file_row.path()) {
+                // Excluded by selective sync, only the local copy goes
+                if selective.is_excluded(file_row.path()) {
+                    remove_local_copy(root, conn, init_dir, file_row).await;
+                    continue;
+                }
+
+                // Ignoring a file stops it syncing, it doesn't delete it from the server
+                if remote_relative_path(file_row.path(), init_dir).is_some_and(|p| rules.is_ignored(&root.join(p), false)) {
+                    continue;
+                }
+
+                if unchanged(file_row)
+                    && let Some(dest) = new_files.get_mut(file_row.hash()).and_then(|paths| paths.pop())
+                    && move_untracked(conn, file_row, &dest, &root_dir).await {
+                    continue;
+                }
+
                 // Proceed to delete path from db
                 println!("Deleting file {}", file_row.path());
                 db::remove_file(conn, &file_row.path().to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
-                delete_paths.push(file_row.path().to_string());
+                queue_op(conn, &file_row.path().to_string(), OpKind::Delete, &root_dir);
             }
         }
     }
 
-    if all_files.len() > 0 {
-        // Upload new/modified files
-        match apis::file::upload_files(all_files).await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Error uploading files. {}", e);
+    // Directories that are gone, deepest first
+    let directories = db::get_directories(conn, &root_dir).unwrap_or_else(|e| {
+        eprintln!("Error getting directories. {}", e);
+        Vec::new()
+    });
+
+    for directory in directories.iter().filter(|directory| !directory_paths.contains(*directory)) {
+        let Some(relative_path) = remote_relative_path(directory, init_dir) else {
+            continue;
+        };
+
+        if rules.is_ignored(&root.join(&relative_path), true) {
+            continue;
+        }
+
+        // Excluded by selective sync, the directory only goes locally and only once it's empty
+        if selective.is_excluded(directory) {
+            if std::fs::remove_dir(root.join(&relative_path)).is_ok() {
+                println!("Removing local directory {}, excluded from sync", directory);
             }
+            db::remove_directory(conn, directory, &root_dir).unwrap_or_else(|e| eprintln!("Error deleting directory. {}", e));
+            continue;
         }
+
+        println!("Deleting directory {}", directory);
+        db::remove_directory(conn, directory, &root_dir).unwrap_or_else(|e| eprintln!("Error deleting directory. {}", e));
+        queue_op(conn, directory, OpKind::Delete, &root_dir);
     }
 
-    if delete_paths.len() > 0 {
-        // Delete files
-        for path in delete_paths {
-            match apis::file::delete_file(path.clone()).await {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error deleting file {}. {}", path, e);
+    // Pull new/newer files down from the server, going through the change journal once we have a cursor into it
+    match db::get_cursor(conn, &root_dir) {
+        Ok(Some(cursor)) => catch_up(root, conn, init_dir, &rules, cursor).await,
+        Ok(None) => pull(root, conn, init_dir, &rules, &file_paths).await,
+        Err(e) => eprintln!("Error getting sync cursor. {}", e),
+    }
+
+    // Changes queued before a restart get another go now rather than waiting out their backoff
+    db::retry_ops_now(conn, &root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+    process_pending_ops(root, conn, &root_dir, policy).await;
+
+}
+
+// What a file on disk looked like when it was hashed
+pub struct DiskEntry {
+    pub hash: String,
+    pub last_modified: DateTime<Utc>,
+    pub mode: Option<u32>,
+    pub size: u64,
+    pub inode: Option<u64>,
+}
+
+// Read and hash what's on disk at a path, or None if it can't be read or changes while being read.
+// A link synced as a link is hashed by its target
+pub fn read_entry(path: &Path, as_link: bool) -> Option<DiskEntry> {
+    if as_link {
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+        return Some(DiskEntry {
+            hash: utils::hash_link(path)?,
+            last_modified: DateTime::<Utc>::from(metadata.modified().ok()?),
+            mode: None,
+            size: metadata.len(),
+            inode: utils::file_inode(&metadata),
+        });
+    }
+
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    let hash = utils::hash_file(&file)?;
+
+    // Written to while it was being hashed, so the hash may not match any complete version of it
+    let after = file.metadata().ok()?;
+    if after.len() != metadata.len() || after.modified().ok() != metadata.modified().ok() {
+        return None;
+    }
+
+    Some(DiskEntry {
+        hash,
+        last_modified: DateTime::<Utc>::from(metadata.modified().ok()?),
+        mode: utils::file_mode(&metadata),
+        size: metadata.len(),
+        inode: utils::file_inode(&metadata),
+    })
+}
+
+// Remember the size, modified time and inode a file was hashed at, returning whether any of them moved
+pub fn note_disk(file_row: &mut FileRow, entry: &DiskEntry) -> bool {
+    let moved = file_row.size() != Some(entry.size)
+        || file_row.inode() != entry.inode
+        || file_row.last_modified() != entry.last_modified;
+
+    file_row.set_size(Some(entry.size));
+    file_row.set_inode(entry.inode);
+    file_row.set_last_modified(entry.last_modified);
+    moved
+}
+
+// Whether a file is the same size, modified time, inode and permissions as when it was last hashed, so it needn't be read again
+pub fn matches_disk(file_row: &FileRow, path: &Path, as_link: bool) -> bool {
+    let metadata = if as_link { std::fs::symlink_metadata(path) } else { std::fs::metadata(path) };
+    let Ok(metadata) = metadata else {
+        return false;
+    };
+
+    file_row.is_symlink() == as_link
+        && file_row.size() == Some(metadata.len())
+        && file_row.inode() == utils::file_inode(&metadata)
+        && metadata.modified().is_ok_and(|modified| DateTime::<Utc>::from(modified) == file_row.last_modified())
+        && (as_link || file_row.mode() == utils::file_mode(&metadata))
+}
+
+// Whether the symlink policy leaves a path out. Links are skipped, followed only when they lead somewhere inside
+// the watched directory, or synced as links; anything reached through a followed link has to be inside it as well
+pub fn link_excluded(symlinks: SymlinkPolicy, path: &Path, root: &Path) -> bool {
+    match symlinks {
+        SymlinkPolicy::Skip => path.is_symlink(),
+        SymlinkPolicy::FollowWithinRoot => {
+            (path.is_symlink() || path.exists()) && !std::fs::canonicalize(path).is_ok_and(|p| p.starts_with(root))
+        }
+        SymlinkPolicy::Preserve => false,
+    }
+}
+
+// Take on a file's current permissions. A change to them alone leaves the contents as the server has them,
+// so the file no longer counts as synced until it has been uploaded again
+pub fn record_mode(file_row: &mut FileRow, hash: &str, mode: Option<u32>) {
+    if file_row.hash() == hash && file_row.mode() != mode && unchanged(file_row) {
+        file_row.set_base_hash(None);
+    }
+
+    file_row.set_mode(mode);
+}
+
+// Record a change to send to the server, it stays queued until the server acknowledges it
+pub fn queue_op(conn: &Connection, path: &String, kind: OpKind, root_dir: &String) {
+    db::queue_op(conn, path, kind, root_dir).unwrap_or_else(|e| eprintln!("Error queueing {} of {}. {}", kind.as_str(), path, e));
+}
+
+// Start tracking a directory, creating it on the server if it's new
+fn track_directory(conn: &Connection, directory: &String, root_dir: &String) {
+    match db::insert_directory(conn, directory, root_dir) {
+        Ok(true) => {
+            println!("New directory {}", directory);
+            queue_op(conn, directory, OpKind::CreateDirectory, root_dir);
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to insert new directory: {:?}", e),
+    }
+}
+
+// A directory appeared in the tree, track it and any directories already inside it
+pub fn track_directories(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &Path) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let selective = SelectiveSync::load(conn);
+
+    for entry in WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|x| {
+            x.file_type().is_dir()
+                && !rules.is_ignored(x.path(), true)
+                && !tracked_path(x.path(), root, init_dir).is_some_and(|p| selective.is_excluded(&p))
+        })
+        .filter_map(|x| x.ok())
+    {
+        if let Some(directory) = tracked_path(entry.path(), root, init_dir) {
+            track_directory(conn, &directory, &root_dir);
+        }
+    }
+}
+
+// Move the server copy of a file that disappeared to the untracked file with the same contents,
+// returning false if the server wouldn't so it gets deleted and uploaded instead
+async fn move_untracked(conn: &Connection, file_row: &FileRow, dest: &String, root_dir: &String) -> bool {
+    println!("Moving file {} to {}", file_row.path(), dest);
+    if let Err(e) = apis::file::move_file(file_row.path(), dest).await {
+        eprintln!("Error moving {} on the server. {}", file_row.path(), e);
+        return false;
+    }
+
+    db::set_base_hash(conn, dest, &file_row.hash().to_string(), root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+    db::remove_op(conn, dest, root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+    db::remove_file(conn, &file_row.path().to_string(), root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
+    true
+}
+
+// A file or directory left the watched tree, delete whatever was tracked there with one request,
+// returning false when nothing was
+pub fn forget_path(conn: &Connection, path: &String, root_dir: &String) -> bool {
+    let file_rows = match db::get_file(conn, path, root_dir) {
+        Ok(file_rows) if !file_rows.is_empty() => file_rows,
+        Ok(_) => db::get_files_under(conn, path, root_dir).unwrap_or_else(|e| {
+            eprintln!("Error getting file rows. {}", e);
+            Vec::new()
+        }),
+        Err(e) => {
+            eprintln!("Error making query. {}", e);
+            Vec::new()
+        }
+    };
+
+    let directories = db::get_directories_under(conn, path, root_dir).unwrap_or_else(|e| {
+        eprintln!("Error getting directories. {}", e);
+        Vec::new()
+    });
+
+    if file_rows.is_empty() && directories.is_empty() {
+        return false;
+    }
+
+    for file_row in file_rows {
+        db::remove_file(conn, &file_row.path().to_string(), root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
+    }
+
+    for directory in directories {
+        db::remove_directory(conn, &directory, root_dir).unwrap_or_else(|e| eprintln!("Error deleting directory. {}", e));
+    }
+
+    // Deleting the directory on the server covers anything still queued inside it
+    db::remove_ops_under(conn, path, root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+    queue_op(conn, path, OpKind::Delete, root_dir);
+    true
+}
+
+// Carry a local rename of a file or directory over to the server, returning false when nothing tracked was at from
+pub async fn move_path(conn: &Connection, from: &String, to: &String, root_dir: &String) -> bool {
+    let file_rows = match db::get_file(conn, from, root_dir) {
+        Ok(file_rows) if !file_rows.is_empty() => file_rows,
+        Ok(_) => db::get_files_under(conn, from, root_dir).unwrap_or_else(|e| {
+            eprintln!("Error getting file rows. {}", e);
+            Vec::new()
+        }),
+        Err(e) => {
+            eprintln!("Error making query. {}", e);
+            Vec::new()
+        }
+    };
+
+    // Only a directory has directories inside it
+    let directories = db::get_directories_under(conn, from, root_dir).unwrap_or_else(|e| {
+        eprintln!("Error getting directories. {}", e);
+        Vec::new()
+    });
+
+    if file_rows.is_empty() && directories.is_empty() {
+        return false;
+    }
+
+    let ops = db::get_pending_ops(conn, root_dir)
+        .unwrap_or_else(|e| {
+            eprintln!("Error getting pending ops. {}", e);
+            Vec::new()
+        })
+        .into_iter()
+        .map(|op| (op.path().to_string(), op.kind()))
+        .collect::<HashMap<String, OpKind>>();
+
+    // Each file's row, new path and whether it replaced a tracked file there
+    let mut moves: Vec<(FileRow, String, bool)> = Vec::new();
+
+    for file_row in file_rows {
+        let dest = format!("{}{}", to, &file_row.path()[from.len()..]);
+        let dest_rows = db::get_file(conn, &dest, root_dir).unwrap_or_else(|e| {
+            eprintln!("Error making query. {}", e);
+            Vec::new()
+        });
+
+        let replaced = match dest_rows.into_iter().next() {
+            // Renamed over a tracked file, which now just has new contents
+            Some(mut dest_row) => {
+                dest_row.set_hash(file_row.hash().to_string());
+                dest_row.set_last_modified(file_row.last_modified());
+                dest_row.set_mode(file_row.mode());
+                dest_row.set_symlink(file_row.is_symlink());
+                db::update_file(conn, &dest_row, root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+                db::remove_file(conn, &file_row.path().to_string(), root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
+                true
+            }
+            None => {
+                db::move_file(conn, &file_row.path().to_string(), &dest, root_dir).unwrap_or_else(|e| eprintln!("Error moving file. {}", e));
+                false
+            }
+        };
+
+        moves.push((file_row, dest, replaced));
+    }
+
+    // Each directory's old and new path and whether one was tracked there already
+    let mut directory_moves: Vec<(String, String, bool)> = Vec::new();
+
+    for directory in directories {
+        let dest = format!("{}{}", to, &directory[from.len()..]);
+        let replaced = db::directory_exists(conn, &dest, root_dir).unwrap_or_else(|e| {
+            eprintln!("Error making query. {}", e);
+            false
+        });
+
+        if replaced {
+            db::remove_directory(conn, &directory, root_dir).unwrap_or_else(|e| eprintln!("Error deleting directory. {}", e));
+        } else {
+            db::move_directory(conn, &directory, &dest, root_dir).unwrap_or_else(|e| eprintln!("Error moving directory. {}", e));
+        }
+
+        directory_moves.push((directory, dest, replaced));
+    }
+
+    println!("Moved {} to {}", from, to);
+    let replaced = moves.iter().any(|(_, _, replaced)| *replaced) || directory_moves.iter().any(|(_, _, replaced)| *replaced);
+    let moved = !replaced && match apis::file::move_file(from, to).await {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Error moving {} on the server, uploading it again. {}", from, e);
+            false
+        }
+    };
+
+    for (file_row, dest, replaced) in moves {
+        let path = file_row.path().to_string();
+
+        if moved {
+            // Whatever was still queued for the file follows it to its new path
+            if ops.contains_key(&path) {
+                db::remove_op(conn, &path, root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+            }
+            if ops.get(&path) == Some(&OpKind::Upload) {
+                queue_op(conn, &dest, OpKind::Upload, root_dir);
+            }
+        } else {
+            // The server only knows the file by its old path
+            if !replaced {
+                db::clear_base_hash(conn, &dest, root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+            }
+            queue_op(conn, &path, OpKind::Delete, root_dir);
+            queue_op(conn, &dest, OpKind::Upload, root_dir);
+        }
+    }
+
+    for (directory, dest, replaced) in directory_moves {
+        if moved {
+            if ops.contains_key(&directory) {
+                db::remove_op(conn, &directory, root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+            }
+            if ops.get(&directory) == Some(&OpKind::CreateDirectory) {
+                queue_op(conn, &dest, OpKind::CreateDirectory, root_dir);
+            }
+        } else {
+            queue_op(conn, &directory, OpKind::Delete, root_dir);
+            if !replaced {
+                queue_op(conn, &dest, OpKind::CreateDirectory, root_dir);
+            }
+        }
+    }
+
+    true
+}
+
+// Send every queued change that is due, backing off the ones that fail
+pub async fn process_pending_ops(root: &Path, conn: &Connection, root_dir: &String, policy: ConflictPolicy) {
+    let ops = match db::get_due_ops(conn, root_dir, Utc::now()) {
+        Ok(ops) => ops,
+        Err(e) => {
+            eprintln!("Error getting pending ops. {}", e);
+            return;
+        }
+    };
+
+    let mut upload_files: Vec<FileRow> = Vec::new();
+    let mut attempts: HashMap<String, u32> = HashMap::new();
+
+    for op in ops {
+        let path = op.path().to_string();
+        attempts.insert(path.clone(), op.attempts());
+
+        match op.kind() {
+            OpKind::Upload => {
+                let file_rows = match db::get_file(conn, &path, root_dir) {
+                    Ok(file_rows) => file_rows,
+                    Err(e) => {
+                        eprintln!("Error making query. {}", e);
+                        continue;
+                    }
+                };
+
+                match file_rows.into_iter().next() {
+                    // Already matches what the server acknowledged last
+                    Some(file_row) if file_row.base_hash() == Some(file_row.hash()) => complete_op(conn, &path, root_dir),
+                    Some(file_row) => upload_files.push(file_row),
+                    None => complete_op(conn, &path, root_dir),
+                }
+            }
+
+            OpKind::Delete => {
+                match apis::file::delete_file(path.clone()).await {
+                    Ok(_) => complete_op(conn, &path, root_dir),
+                    Err(e) => {
+                        eprintln!("Error deleting file {}. {}", path, e);
+                        retry_op(conn, &path, op.attempts(), e.to_string(), root_dir);
+                    }
+                }
+            }
+
+            OpKind::CreateDirectory => {
+                // Gone again before the server heard about it
+                if !db::directory_exists(conn, &path, root_dir).unwrap_or(true) {
+                    complete_op(conn, &path, root_dir);
+                    continue;
+                }
+
+                match apis::file::create_directory(&path).await {
+                    Ok(_) => complete_op(conn, &path, root_dir),
+                    Err(e) => {
+                        eprintln!("Error creating directory {}. {}", path, e);
+                        retry_op(conn, &path, op.attempts(), e.to_string(), root_dir);
+                    }
                 }
             }
         }
     }
 
-}
\ No newline at end of file
+    if !upload_files.is_empty() {
+        push_files(root, upload_files, conn, root_dir, policy, &attempts).await;
+    }
+}
+
+fn complete_op(conn: &Connection, path: &String, root_dir: &String) {
+    db::remove_op(conn, path, root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+}
+
+// Try again later, waiting twice as long after every failed attempt
+fn retry_op(conn: &Connection, path: &String, attempts: u32, error: String, root_dir: &String) {
+    let delay = RETRY_BASE_DELAY.checked_mul(1 << attempts.min(10)).unwrap_or(RETRY_MAX_DELAY).min(RETRY_MAX_DELAY);
+    println!("Retrying {} in {}s", path, delay.num_seconds());
+
+    db::reschedule_op(conn, path, Utc::now() + delay, &error, root_dir)
+        .unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+}
+
+// Upload files, record the ones the server acknowledged and settle any conflicts
+async fn push_files(root: &Path, files: Vec<FileRow>, conn: &Connection, root_dir: &String, policy: ConflictPolicy, attempts: &HashMap<String, u32>) {
+    let attempts_for = |path: &str| attempts.get(path).copied().unwrap_or(0);
+    let paths = files.iter().map(|file| file.path().to_string()).collect::<Vec<String>>();
+
+    let result = match apis::file::upload_files(files).await {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error uploading files. {}", e);
+            for path in paths {
+                retry_op(conn, &path, attempts_for(&path), e.to_string(), root_dir);
+            }
+            return;
+        }
+    };
+
+    for file in result.uploaded {
+        let path = file.path().to_string();
+        db::set_base_hash(conn, &path, &file.hash().to_string(), root_dir)
+            .unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+        complete_op(conn, &path, root_dir);
+    }
+
+    for (file, error) in result.failed {
+        eprintln!("Failed to upload {}. {}", file.path(), error);
+        retry_op(conn, &file.path().to_string(), attempts_for(file.path()), error, root_dir);
+    }
+
+    for (file, server_file) in result.conflicts {
+        let path = file.path().to_string();
+        println!("Conflict on {}, resolving with {:?}", path, policy);
+
+        match resolve_conflict(root, file, server_file, conn, root_dir, policy).await {
+            Ok(files) => {
+                // Whatever the resolution left to upload goes back on the queue
+                complete_op(conn, &path, root_dir);
+                for file in files {
+                    queue_op(conn, &file.path().to_string(), OpKind::Upload, root_dir);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error resolving conflict. {}", e);
+                retry_op(conn, &path, attempts_for(&path), e.to_string(), root_dir);
+            }
+        }
+    }
+}
+
+// Apply the conflict policy to a file changed both locally and on the server,
+// returning any files that should be uploaded again
+async fn resolve_conflict(
+    root: &Path,
+    file: FileRow,
+    server_file: FileRow,
+    conn: &Connection,
+    root_dir: &String,
+    policy: ConflictPolicy
+) -> Result<Vec<FileRow>, Box<dyn Error>> {
+    let prefer_local = match policy {
+        ConflictPolicy::PreferLocal => true,
+        ConflictPolicy::PreferRemote => false,
+        ConflictPolicy::PreferNewest => file.last_modified() > server_file.last_modified(),
+        ConflictPolicy::KeepBoth => return keep_both(root, file, server_file, conn, root_dir).await,
+    };
+
+    if prefer_local {
+        // Upload again on top of the server copy we now know about
+        db::set_base_hash(conn, &file.path().to_string(), &server_file.hash().to_string(), root_dir)?;
+        return Ok(vec![file]);
+    }
+
+    let local_path = local_file_path(root, file.path(), root_dir)?;
+    let downloaded = apis::file::download_file(server_file.path().to_string(), &local_path, root).await?;
+    db::update_file(conn, &downloaded, root_dir)?;
+
+    Ok(Vec::new())
+}
+
+// Move the local file aside as a conflict copy and take the server copy in its place
+async fn keep_both(root: &Path, file: FileRow, server_file: FileRow, conn: &Connection, root_dir: &String) -> Result<Vec<FileRow>, Box<dyn Error>> {
+    let local_path = local_file_path(root, file.path(), root_dir)?;
+    let conflict_path = utils::conflict_file_path(&local_path, &utils::device_name(), Utc::now());
+    fs::rename(&local_path, &conflict_path).await?;
+    println!("Saved local copy of {} as {}", file.path(), conflict_path.display());
+
+    let downloaded = apis::file::download_file(server_file.path().to_string(), &local_path, root).await?;
+    db::update_file(conn, &downloaded, root_dir)?;
+
+    let relative_path = conflict_path.strip_prefix(root)
+        .map_err(|_| format!("Conflict copy {} is outside the watched directory", conflict_path.display()))?;
+    let mut conflict_file = utils::convert_to_file_row(
+        utils::format_file_path(&Path::new(root_dir).join(relative_path).to_string_lossy().to_string()),
+        file.hash().to_string(),
+        file.last_modified()
+    );
+    conflict_file.set_mode(file.mode());
+    conflict_file.set_symlink(file.is_symlink());
+    db::insert_file(conn, &conflict_file, root_dir)?;
+
+    Ok(vec![conflict_file])
+}
+
+// Where a file tracked in the DB is on disk
+fn local_file_path(root: &Path, path: &str, root_dir: &String) -> Result<PathBuf, Box<dyn Error>> {
+    match utils::relative_to_root(path, Path::new(root_dir)) {
+        Some(relative_path) => Ok(root.join(relative_path)),
+        None => Err(Box::from(format!("{} is outside the watched directory", path))),
+    }
+}
+
+// Download files present on the server that are missing or older locally, comparing against the full listing
+async fn pull(
+    root: &Path,
+    conn: &Connection,
+    init_dir: &Path,
+    rules: &IgnoreRules,
+    local_paths: &HashMap<String, u8>
+) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let deleted_paths = pending_deletes(conn, &root_dir);
+    let selective = SelectiveSync::load(conn);
+
+    // Note where the journal is up to first, anything that changes during the listing gets replayed next time
+    let cursor = match apis::file::list_changes(None).await {
+        Ok(changes) => changes.cursor,
+        Err(e) => {
+            eprintln!("Error getting change cursor. {}", e);
+            return;
+        }
+    };
+
+    // Directories first, so empty ones exist and files have somewhere to go
+    let remote_directories = match apis::file::list_directories().await {
+        Ok(directories) => directories,
+        Err(e) => {
+            eprintln!("Error listing remote directories. {}", e);
+            return;
+        }
+    };
+
+    for directory in remote_directories {
+        apply_directory_created(root, conn, init_dir, rules, &directory).await;
+    }
+
+    let remote_files = match apis::file::list_files().await {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error listing remote files. {}", e);
+            return;
+        }
+    };
+
+    for remote_file in remote_files {
+        // Skip files belonging to other watched directories
+        let relative_path = match remote_relative_path(remote_file.path(), init_dir) {
+            Some(p) if !rules.is_ignored(&root.join(&p), false) => p,
+            _ => continue,
+        };
+
+        // Deleted locally, the server copy is about to be removed
+        if is_pending_delete(&deleted_paths, remote_file.path()) {
+            continue;
+        }
+
+        // Not wanted on this device
+        if selective.is_excluded(remote_file.path()) {
+            continue;
+        }
+
+        let file_rows = db::get_file(conn, &remote_file.path().to_string(), &root_dir).unwrap_or_else(|e| {
+            eprintln!("Error making query. {}", e);
+            Vec::new()
+        });
+
+        // Only take the server copy over a local file that hasn't changed since the last sync,
+        // anything else is either up to date or a conflict the upload will report
+        let should_download = match file_rows.first() {
+            Some(file_row) if local_paths.contains_key(remote_file.path()) => {
+                file_row.hash() != remote_file.hash() && file_row.base_hash() == Some(file_row.hash())
+            }
+            _ => true,
+        };
+
+        if should_download {
+            download(root, conn, &root_dir, remote_file.path(), &relative_path, file_rows.is_empty()).await;
+        } else if let Some(file_row) = file_rows.first() {
+            update_mode(root, conn, &root_dir, &relative_path, file_row, remote_file.hash(), remote_file.mode());
+        }
+    }
+
+    db::set_cursor(conn, cursor, &root_dir).unwrap_or_else(|e| eprintln!("Error saving sync cursor. {}", e));
+}
+
+// Apply every change the server journaled after cursor, saving progress as each page is done
+async fn catch_up(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, mut cursor: i64) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+
+    loop {
+        let page = match apis::file::list_changes(Some(cursor)).await {
+            Ok(page) => page,
+            Err(e) => {
+                eprintln!("Error listing changes. {}", e);
+                return;
+            }
+        };
+
+        // Only the last change to each path still matters, the server has moved on from the rest
+        let mut last_changes: HashMap<&str, i64> = HashMap::new();
+        for change in &page.changes {
+            last_changes.insert(change.event().path(), change.cursor());
+            if let Some(from) = change.event().from() {
+                last_changes.insert(from, change.cursor());
+            }
+        }
+        let is_last = |path: &str, cursor: i64| last_changes.get(path) == Some(&cursor);
+
+        for change in &page.changes {
+            let event = change.event();
+            if is_last(event.path(), change.cursor()) {
+                apply_change(root, conn, init_dir, rules, event).await;
+            } else if let Some(from) = event.from().filter(|from| is_last(from, change.cursor())) {
+                // Whatever happened at the destination later, the file is gone from where it was
+                apply_delete(root, conn, init_dir, rules, from).await;
+            }
+        }
+
+        cursor = page.cursor;
+        db::set_cursor(conn, cursor, &root_dir).unwrap_or_else(|e| eprintln!("Error saving sync cursor. {}", e));
+
+        if !page.more {
+            return;
+        }
+    }
+}
+
+// Apply a change another device made, as announced by the server's event stream
+pub async fn apply_remote_event(root: &PathBuf, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, policy: ConflictPolicy, symlinks: SymlinkPolicy, event: FileEvent) {
+    if event.kind() != FileEventKind::Resync {
+        apply_change(root, conn, init_dir, rules, &event).await;
+        return;
+    }
+
+    // Events may have been missed, the journal has everything since the last catch up
+    match db::get_cursor(conn, &init_dir.to_string_lossy().to_string()) {
+        Ok(Some(cursor)) => catch_up(root, conn, init_dir, rules, cursor).await,
+        Ok(None) => sync(root, conn, init_dir, policy, symlinks).await,
+        Err(e) => eprintln!("Error getting sync cursor. {}", e),
+    }
+}
+
+// Bring a local file in line with a server change, unless it has local changes of its own
+async fn apply_change(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, event: &FileEvent) {
+    match (event.kind(), event.from()) {
+        (FileEventKind::Created | FileEventKind::Updated, _) => apply_update(root, conn, init_dir, rules, event.path(), event.hash(), event.mode()).await,
+        (FileEventKind::Deleted, _) => apply_delete(root, conn, init_dir, rules, event.path()).await,
+        (FileEventKind::Moved, Some(from)) => {
+            // Otherwise it's one file going and another arriving
+            if !apply_move(root, conn, init_dir, rules, from, event.path(), event.hash()).await {
+                apply_delete(root, conn, init_dir, rules, from).await;
+                apply_update(root, conn, init_dir, rules, event.path(), event.hash(), event.mode()).await;
+            }
+        }
+        (FileEventKind::DirectoryCreated, _) => apply_directory_created(root, conn, init_dir, rules, event.path()).await,
+        (FileEventKind::DirectoryDeleted, _) => apply_directory_deleted(root, conn, init_dir, rules, event.path()).await,
+        (FileEventKind::Moved, None) | (FileEventKind::Resync, _) => {}
+    }
+}
+
+async fn apply_update(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str, hash: Option<&str>, mode: Option<u32>) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let Some((relative_path, file_row)) = change_target(root, conn, init_dir, rules, path) else {
+        return;
+    };
+
+    let should_download = match &file_row {
+        Some(file_row) => hash != Some(file_row.hash()) && unchanged(file_row),
+        None => !root.join(&relative_path).exists(),
+    };
+
+    if should_download {
+        download(root, conn, &root_dir, path, &relative_path, file_row.is_none()).await;
+    } else if let (Some(file_row), Some(hash)) = (&file_row, hash) {
+        update_mode(root, conn, &root_dir, &relative_path, file_row, hash, mode);
+    }
+}
+
+// Same contents as the server copy but different permissions, only the permissions need to change
+fn update_mode(root: &Path, conn: &Connection, root_dir: &String, relative_path: &Path, file_row: &FileRow, hash: &str, mode: Option<u32>) {
+    let Some(mode) = mode.filter(|mode| file_row.hash() == hash && file_row.mode() != Some(*mode) && unchanged(file_row)) else {
+        return;
+    };
+
+    println!("Updating permissions of {}", file_row.path());
+    if let Err(e) = utils::set_file_mode(&root.join(relative_path), mode) {
+        eprintln!("Error setting permissions of {}. {}", file_row.path(), e);
+        return;
+    }
+
+    let mut file_row = file_row.clone();
+    file_row.set_mode(Some(mode));
+    db::update_file(conn, &file_row, root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+}
+
+async fn apply_delete(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let Some((relative_path, Some(file_row))) = change_target(root, conn, init_dir, rules, path) else {
+        return;
+    };
+
+    if !unchanged(&file_row) {
+        return;
+    }
+
+    println!("Deleting file {} removed on the server", path);
+    if let Err(e) = fs::remove_file(root.join(&relative_path)).await {
+        eprintln!("Error deleting file {}. {}", path, e);
+        return;
+    }
+
+    db::remove_file(conn, &path.to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
+    db::remove_op(conn, &path.to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+}
+
+async fn apply_directory_created(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let Some(relative_path) = local_target(root, conn, init_dir, rules, path, true) else {
+        return;
+    };
+
+    let directory = path.to_string();
+    if db::directory_exists(conn, &directory, &root_dir).unwrap_or(false) {
+        return;
+    }
+
+    let local_path = root.join(&relative_path);
+    if !local_path.is_dir() {
+        println!("Creating directory {}", path);
+        if let Err(e) = fs::create_dir_all(&local_path).await {
+            eprintln!("Error creating directory {}. {}", path, e);
+            return;
+        }
+    }
+
+    db::insert_directory(conn, &directory, &root_dir).unwrap_or_else(|e| {
+        eprintln!("Failed to insert new directory: {:?}", e);
+        false
+    });
+}
+
+async fn apply_directory_deleted(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let Some(relative_path) = local_target(root, conn, init_dir, rules, path, true) else {
+        return;
+    };
+
+    let directory = path.to_string();
+    if !db::directory_exists(conn, &directory, &root_dir).unwrap_or(false) {
+        return;
+    }
+
+    match fs::remove_dir(root.join(&relative_path)).await {
+        Ok(_) => println!("Deleting directory {} removed on the server", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(_) => {
+            // Still holds files the server doesn't have, so it goes back up with them
+            queue_op(conn, &directory, OpKind::CreateDirectory, &root_dir);
+            return;
+        }
+    }
+
+    db::remove_directory(conn, &directory, &root_dir).unwrap_or_else(|e| eprintln!("Error deleting directory. {}", e));
+    db::remove_op(conn, &directory, &root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+}
+
+// Move the local copy of a file the server moved, returning whether it could be
+async fn apply_move(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, from: &str, to: &str, hash: Option<&str>) -> bool {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let (Some((from_relative, Some(file_row))), Some((to_relative, None))) = (change_target(root, conn, init_dir, rules, from), change_target(root, conn, init_dir, rules, to)) else {
+        return false;
+    };
+
+    let source = root.join(&from_relative);
+    let dest = root.join(&to_relative);
+    if !unchanged(&file_row) || hash != Some(file_row.hash()) || dest.exists() {
+        return false;
+    }
+
+    println!("Moving file {} to {}", from, to);
+    if let Some(parent) = dest.parent()
+        && let Err(e) = fs::create_dir_all(parent).await {
+        eprintln!("Error creating directory {}. {}", parent.display(), e);
+        return false;
+    }
+
+    if let Err(e) = fs::rename(&source, &dest).await {
+        eprintln!("Error moving file {}. {}", from, e);
+        return false;
+    }
+
+    db::move_file(conn, &from.to_string(), &to.to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error moving file. {}", e));
+
+    remove_empty_dirs(root, conn, init_dir, &source);
+    true
+}
+
+// Delete the local copy of a file left out by selective sync, keeping it if it has changes the server hasn't got
+async fn remove_local_copy(root: &Path, conn: &Connection, init_dir: &Path, file_row: &FileRow) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let Some(relative_path) = remote_relative_path(file_row.path(), init_dir) else {
+        return;
+    };
+
+    let local_path = root.join(&relative_path);
+    let exists = local_path.symlink_metadata().is_ok();
+    let local_hash = if file_row.is_symlink() {
+        utils::hash_link(&local_path)
+    } else {
+        utils::hash_filepath(&local_path)
+    };
+
+    if !unchanged(file_row) || (exists && local_hash.as_deref() != Some(file_row.hash())) {
+        return;
+    }
+
+    println!("Removing local copy of {}, excluded from sync", file_row.path());
+    if exists && let Err(e) = fs::remove_file(&local_path).await {
+        eprintln!("Error deleting file {}. {}", file_row.path(), e);
+        return;
+    }
+
+    db::remove_file(conn, &file_row.path().to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error deleting file. {}", e));
+    db::remove_op(conn, &file_row.path().to_string(), &root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+    remove_empty_dirs(root, conn, init_dir, &local_path);
+}
+
+// Tidy up the untracked directories above a removed file that it left empty
+fn remove_empty_dirs(root: &Path, conn: &Connection, init_dir: &Path, path: &Path) {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let mut parent = path.parent();
+    while let Some(dir) = parent.filter(|dir| *dir != root) {
+        let tracked = tracked_path(dir, root, init_dir).is_some_and(|directory| db::directory_exists(conn, &directory, &root_dir).unwrap_or(true));
+        if tracked || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+// Where a server path is locally and its row, None when the change isn't ours to apply
+fn change_target(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str) -> Option<(PathBuf, Option<FileRow>)> {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let relative_path = local_target(root, conn, init_dir, rules, path, false)?;
+
+    match db::get_file(conn, &path.to_string(), &root_dir) {
+        Ok(file_rows) => Some((relative_path, file_rows.into_iter().next())),
+        Err(e) => {
+            eprintln!("Error making query. {}", e);
+            None
+        }
+    }
+}
+
+// Where a server path is locally, None when it isn't synced here
+fn local_target(root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, path: &str, is_dir: bool) -> Option<PathBuf> {
+    let root_dir = init_dir.to_string_lossy().to_string();
+    let relative_path = remote_relative_path(path, init_dir)?;
+
+    // Ignored locally, whatever the server has
+    if rules.is_ignored(&root.join(&relative_path), is_dir) {
+        return None;
+    }
+
+    // Deleted locally, the server copy is about to be removed
+    if is_pending_delete(&pending_deletes(conn, &root_dir), path) {
+        return None;
+    }
+
+    // Not wanted on this device
+    if SelectiveSync::load(conn).is_excluded(path) {
+        return None;
+    }
+
+    Some(relative_path)
+}
+
+// A local change still waiting to go up wins, the upload reports any conflict
+fn unchanged(file_row: &FileRow) -> bool {
+    file_row.base_hash() == Some(file_row.hash())
+}
+
+// Paths with a delete waiting to go up to the server
+fn pending_deletes(conn: &Connection, root_dir: &String) -> HashSet<String> {
+    db::get_pending_ops(conn, root_dir)
+        .unwrap_or_else(|e| {
+            eprintln!("Error getting pending ops. {}", e);
+            Vec::new()
+        })
+        .into_iter()
+        .filter(|op| op.kind() == OpKind::Delete)
+        .map(|op| op.path().to_string())
+        .collect::<HashSet<String>>()
+}
+
+// Path of a watched file as stored in the DB
+pub fn tracked_path(path: &Path, watch_root: &Path, init_dir: &Path) -> Option<String> {
+    let relative_path = path.strip_prefix(watch_root).ok()?;
+    Some(utils::format_file_path(&init_dir.join(relative_path).to_string_lossy().to_string()))
+}
+
+// Whether a path or a directory above it has a delete waiting to go up
+fn is_pending_delete(deleted_paths: &HashSet<String>, path: &str) -> bool {
+    let mut path = path;
+    loop {
+        if deleted_paths.contains(path) {
+            return true;
+        }
+
+        match path.rsplit_once('/') {
+            Some((parent, _)) => path = parent,
+            None => return false,
+        }
+    }
+}
+
+// Where a server path lives under the watched directory, if it belongs to it at all
+fn remote_relative_path(path: &str, init_dir: &Path) -> Option<PathBuf> {
+    let relative_path = PathBuf::from(utils::relative_to_root(path, init_dir)?);
+
+    if relative_path.is_absolute() || relative_path.components().any(|x| matches!(x, ParentDir)) {
+        eprintln!("Skipping invalid remote path {}", path);
+        return None;
+    }
+
+    Some(relative_path)
+}
+
+// Download the server copy of a file over the local one, recording it as synced
+async fn download(root: &Path, conn: &Connection, root_dir: &String, path: &str, relative_path: &Path, is_new: bool) {
+    println!("Downloading file {}", path);
+    let dest = root.join(relative_path);
+
+    match apis::file::download_file(path.to_string(), &dest, root).await {
+        Ok(file_row) => {
+            if is_new {
+                db::insert_file(conn, &file_row, root_dir).unwrap_or_else(|e| eprintln!("Failed to insert new: {:?}", e));
+            } else {
+                db::update_file(conn, &file_row, root_dir).unwrap_or_else(|e| eprintln!("Error updating file. {}", e));
+            }
+
+            // The server copy replaced the local file, there's nothing left to upload
+            db::remove_op(conn, &path.to_string(), root_dir).unwrap_or_else(|e| eprintln!("Error updating pending ops. {}", e));
+        }
+        Err(e) => {
+            eprintln!("Error downloading file {}. {}", path, e);
+        }
+    }
+}
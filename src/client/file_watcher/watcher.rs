@@ -1,23 +1,24 @@
 // Core logic for file watching
 
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Result, Config, EventKind};
-use std::path::{ PathBuf };
-use std::sync::mpsc::channel;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher, Result, Config};
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
-use std::fs::File;
-use chrono::{DateTime, Utc};
 use rusqlite::Connection;
 use crate::shared::utils;
 use crate::client::{
-    file_watcher::sync,
-    db,
-    apis
+    file_watcher::{ignore_rules, subscriber, sync},
+    file_watcher::coalescer::{Coalescer, NetOp},
+    db
 };
-use async_std::task;
-use crate::shared::models::FileRow;
+use crate::client::file_watcher::ignore_rules::IgnoreRules;
+use crate::client::file_watcher::selective_sync::SelectiveSync;
+use crate::shared::models::{OpKind, SymlinkPolicy};
 
-pub async fn watch_path(watch_root: PathBuf, conn: &Connection, init_dir: &PathBuf) -> Result<()> {
+// How often queued changes are retried while no file events arrive
+const PENDING_OPS_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn watch_path(watch_root: PathBuf, conn: &Connection, init_dir: &Path) -> Result<()> {
     // Check if user has logged in yet
     let config_dir = match utils::get_config_path().await {
         Some(config_dir) => config_dir,
@@ -37,169 +38,243 @@ pub async fn watch_path(watch_root: PathBuf, conn: &Connection, init_dir: &PathB
         return Ok(())
     }
 
+    let (policy, symlinks, rescan_interval) = match utils::load_config().await {
+        Ok(config) => (config.conflict_policy, config.symlink_policy, Duration::from_secs(config.rescan_interval)),
+        Err(e) => {
+            eprintln!("Error loading config: {}", e);
+            return Ok(())
+        }
+    };
+
     // First sync files
     println!("Syncing directory {:?}", watch_root);
-    sync::sync(&watch_root, conn, init_dir).await;
+    sync::sync(&watch_root, conn, init_dir, policy, symlinks).await;
 
     // Channel to receive file change events
     let (tx, rx) = channel();
 
-    // Create and instantiate the watcher
-    let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
+    // Create and instantiate the watcher, only watching through links when they are followed
+    let config = Config::default().with_follow_symlinks(symlinks == SymlinkPolicy::FollowWithinRoot);
+    let mut watcher = RecommendedWatcher::new(tx, config)?;
 
     watcher.watch(&watch_root, RecursiveMode::Recursive)?;
 
     println!("Watching for changes in {:?}", watch_root);
 
-    // Implement debouncer:
-    // A debouncer is a concept used in programming where we want to
-    // filter out multiple calls to a function or event so that it
-    // will only run once during a certain time threshold. In this
-    // instance, we will implement one for the Modify event
-
-    let debounce_time = Duration::from_millis(500);
-    let mut last_event_times = HashMap::<PathBuf, Instant>::new();
-
-    // Loop events that are being received in the channel
-    for res in rx {
-        match res {
-            Ok(event) => {
-                for path in event.paths {
-                    if !utils::check_file_path(&path) {
-                        continue;
-                    }
+    let mut rules = IgnoreRules::load(&watch_root);
+    let mut selective_seen = SelectiveSync::version(conn);
+    let mut selective = SelectiveSync::load(conn);
+
+    // Changes other devices make arrive from the server's event stream
+    let (remote_tx, remote_rx) = channel();
+    subscriber::spawn(remote_tx);
+
+    // Events are collected per path and only acted on once the path goes quiet
+    let mut queue = Coalescer::new();
+
+    let root_dir = init_dir.to_string_lossy().to_string();
+
+    // Notify can miss changes, a full sync every so often catches anything that slipped past
+    let mut last_rescan = Instant::now();
+
+    // Loop events that are being received in the channel, retrying queued changes whenever it goes quiet
+    loop {
+        // The selective sync list was edited from the CLI, bring the local copy in line with it
+        let version = SelectiveSync::version(conn);
+        if version != selective_seen {
+            selective_seen = version;
+            let current = SelectiveSync::load(conn);
+            if current != selective {
+                println!("Selective sync changed, syncing directory {:?}", watch_root);
+                selective = current;
+                sync::sync(&watch_root, conn, init_dir, policy, symlinks).await;
+            }
+        }
+
+        while let Ok(event) = remote_rx.try_recv() {
+            sync::apply_remote_event(&watch_root, conn, init_dir, &rules, policy, symlinks, event).await;
+        }
 
-                    let now = Instant::now();
-                    let should_process = match last_event_times.get(&path) {
-                        Some(last_time) => now.duration_since(*last_time) > debounce_time,
-                        None => true,
-                    };
-
-                    if should_process {
-                        last_event_times.insert(path.clone(), now);
-
-                        task::sleep(Duration::from_millis(100)).await;
-                        let mut root_path = PathBuf::from(init_dir);
-
-                        match &event.kind {
-                            EventKind::Create(_) | EventKind::Modify(_) => {
-                                let file = File::open(&path)?;
-                                if let Some(hash) = utils::hash_file(&file) {
-                                    println!("File {} at {:?} with hash {}",
-                                    if matches!(event.kind, EventKind::Modify(_)) { "Modified" } else { "Created" },
-                                    path, hash);
-
-                                    let relative_path = match path.strip_prefix(&watch_root) {
-                                        Ok(p) => p.to_path_buf(),
-                                        Err(_) => {
-                                            eprintln!("Failed to get relative path for {:?}", path);
-                                            continue;
-                                        }
-                                    };
-
-                                    root_path.push(&relative_path);
-                                    let file_path = utils::format_file_path(&root_path.to_string_lossy().to_string());
-                                    let root_dir = init_dir.to_string_lossy().to_string();
-                                    let file_rows = db::get_file(conn, &file_path, &root_dir).unwrap_or_else(|e| {
-                                        eprintln!("Error getting file row: {}", e);
-                                        Vec::new()
-                                    });
-                                    let file_metadata = file.metadata()?;
-                                    let last_modified = DateTime::<Utc>::from(file_metadata.modified()?);
-                                    if file_rows.len() > 0 {
-                                        // A file exists in our db, lets update it
-                                        let mut file_row = file_rows.get(0).unwrap().clone();
-                                        if file_row.hash() != hash {
-                                            file_row.set_hash(hash);
-                                        }
-
-                                        file_row.set_last_modified(last_modified);
-
-                                        db::update_file(conn, &file_row, &root_dir).unwrap_or_else(|e| {
-                                            eprintln!("Error updating DB entries: {:?}", e);
-                                        });
-
-                                        match apis::file::upload_files(file_rows).await {
-                                            Ok(_) => {}
-                                            Err(e) => {
-                                                eprintln!("Error uploading files: {}", e);
-                                                continue;
-                                            }
-                                        }
-
-                                    } else {
-                                        // This file doesnt exist, lets create an entry
-
-                                        let new_file_row = utils::convert_to_file_row(
-                                            file_path,
-                                            hash,
-                                            last_modified
-                                        );
-
-                                        db::insert_file(conn, &new_file_row, &root_dir).unwrap_or_else(|e| {
-                                            eprintln!("Failed to insert new: {:?}", e);
-                                        });
-
-                                        let mut files = Vec::<FileRow>::new();
-                                        files.push(new_file_row);
-
-                                        match apis::file::upload_files(files).await {
-                                            Ok(_) => {}
-                                            Err(e) => {
-                                                eprintln!("Error uploading files: {}", e);
-                                                continue;
-                                            }
-                                        }
-                                    }
-
-                                    root_path.clear();
-
-                                } else {
-                                    println!("Failed to hash file");
-                                }
-                            }
-
-                            EventKind::Remove(_) => {
-                                let relative_path = match path.strip_prefix(&watch_root) {
-                                    Ok(p) => p.to_path_buf(),
-                                    Err(_) => {
-                                        eprintln!("Failed to get relative path for {:?}", path);
-                                        continue;
-                                    }
-                                };
-                                root_path.push(&relative_path);
-                                let file_path = utils::format_file_path(&root_path.to_string_lossy().to_string());
-                                let root_dir = init_dir.to_string_lossy().to_string();
-                                db::remove_file(conn, &file_path, &root_dir).unwrap_or_else(|e| {
-                                    eprintln!("Failed to remove file: {:?}", e);
-                                });
-                                println!("Removed: {:?}", path);
-                                root_path.clear();
-
-                                match apis::file::delete_file(file_path).await {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        eprintln!("Error deleting file: {}", e);
-                                        continue;
-                                    }
-                                }
-
-                            }
-
-                            _ => {
-
-                            }
-                        }
+        // Wake for whichever comes first, the next path settling or the next retry of queued changes
+        let timeout = queue
+            .next_deadline()
+            .map_or(PENDING_OPS_INTERVAL, |deadline| deadline.saturating_duration_since(Instant::now()).min(PENDING_OPS_INTERVAL));
+
+        match rx.recv_timeout(timeout) {
+            Ok(res) => {
+                // Take everything already waiting so a burst lands in one batch
+                for res in std::iter::once(res).chain(rx.try_iter()) {
+                    match res {
+                        Ok(event) => queue.push(event),
+                        Err(e) => println!("watch error: {:?}", e),
                     }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if queue.take_rescan() || (!rescan_interval.is_zero() && last_rescan.elapsed() >= rescan_interval) {
+            println!("Rescanning directory {:?}", watch_root);
+            last_rescan = Instant::now();
+            sync::sync(&watch_root, conn, init_dir, policy, symlinks).await;
+        }
+
+        let batch = queue.ready();
+
+        // Edited ignore rules can un-ignore files, so look over the whole tree again
+        if batch.iter().any(|(path, _)| ignore_rules::is_ignore_file(path)) {
+            println!("Ignore rules changed, syncing directory {:?}", watch_root);
+            rules = IgnoreRules::load(&watch_root);
+            sync::sync(&watch_root, conn, init_dir, policy, symlinks).await;
+        }
 
+        for (path, op) in batch {
+            // Anything that wasn't tracked at the old path is just a new file at the new one
+            if let NetOp::Renamed(from) = &op {
+                rename_path(from, &path, &watch_root, conn, init_dir, &rules, &root_dir).await;
+            }
+
+            if rules.is_ignored(&path, path.is_dir())
+                || sync::tracked_path(&path, &watch_root, init_dir).is_some_and(|p| selective.is_excluded(&p))
+                || sync::link_excluded(symlinks, &path, &watch_root) {
+                continue;
+            }
 
+            if op == NetOp::Removed {
+                // A directory removed with everything in it is deleted on the server as one
+                let removed = removed_root(&path, &watch_root);
+                let file_path = match sync::tracked_path(removed, &watch_root, init_dir) {
+                    Some(file_path) => file_path,
+                    None => {
+                        eprintln!("Failed to get relative path for {:?}", path);
+                        continue;
+                    }
+                };
+
+                if sync::forget_path(conn, &file_path, &root_dir) {
+                    println!("Removed: {:?}", removed);
                 }
+                continue;
+            }
+
+            let as_link = path.is_symlink() && symlinks == SymlinkPolicy::Preserve;
 
+            // Directories are tracked on their own, so empty ones reach the server too
+            if path.is_dir() && !as_link {
+                sync::track_directories(&watch_root, conn, init_dir, &rules, &path);
+                continue;
+            }
+
+            if !as_link && !utils::check_file_path(&path) {
+                continue;
+            }
+
+            // Open elsewhere or changing as it was read, so give it another go shortly
+            if !record_file(&path, as_link, &watch_root, conn, init_dir, &root_dir) && !queue.retry(path.clone()) {
+                eprintln!("Giving up on {:?} until the next sync, it couldn't be read", path);
             }
-            Err(e) => println!("watch error: {:?}", e)
         }
+
+        sync::process_pending_ops(&watch_root, conn, &root_dir, policy).await;
     }
 
     Ok(())
 
 }
+
+// The highest directory above a removed path that went with it, or the path itself
+fn removed_root<'a>(path: &'a Path, watch_root: &Path) -> &'a Path {
+    let mut removed = path;
+    while let Some(parent) = removed.parent().filter(|parent| *parent != watch_root && parent.starts_with(watch_root) && !parent.exists()) {
+        removed = parent;
+    }
+
+    removed
+}
+
+// Bring the DB row for a created or modified file up to date and queue its upload, unless nothing about it changed.
+// Returns false when the file is still there but couldn't be read
+fn record_file(path: &Path, as_link: bool, watch_root: &Path, conn: &Connection, init_dir: &Path, root_dir: &String) -> bool {
+    let Some(file_path) = sync::tracked_path(path, watch_root, init_dir) else {
+        eprintln!("Failed to get relative path for {:?}", path);
+        return true;
+    };
+
+    let file_rows = db::get_file(conn, &file_path, root_dir).unwrap_or_else(|e| {
+        eprintln!("Error getting file row: {}", e);
+        Vec::new()
+    });
+
+    // Our own downloads land here too, and are just as they were left
+    if file_rows.first().is_some_and(|file_row| sync::matches_disk(file_row, path, as_link)) {
+        return true;
+    }
+
+    let Some(entry) = sync::read_entry(path, as_link) else {
+        println!("Failed to hash file {:?}", path);
+        return path.symlink_metadata().is_err();
+    };
+
+    if let Some(file_row) = file_rows.first() {
+        // Touched without changing what's in it
+        if file_row.hash() == entry.hash && file_row.mode() == entry.mode && file_row.is_symlink() == as_link {
+            let mut file_row = file_row.clone();
+            if sync::note_disk(&mut file_row, &entry) {
+                db::update_file(conn, &file_row, root_dir).unwrap_or_else(|e| {
+                    eprintln!("Error updating DB entries: {:?}", e);
+                });
+            }
+            return true;
+        }
+
+        println!("File Modified at {:?} with hash {}", path, entry.hash);
+
+        // A file exists in our db, lets update it
+        let mut file_row = file_row.clone();
+        sync::record_mode(&mut file_row, &entry.hash, entry.mode);
+        file_row.set_symlink(as_link);
+        sync::note_disk(&mut file_row, &entry);
+        if file_row.hash() != entry.hash {
+            file_row.set_hash(entry.hash);
+        }
+
+        db::update_file(conn, &file_row, root_dir).unwrap_or_else(|e| {
+            eprintln!("Error updating DB entries: {:?}", e);
+        });
+    } else {
+        println!("File Created at {:?} with hash {}", path, entry.hash);
+
+        // This file doesnt exist, lets create an entry
+        let mut new_file_row = utils::convert_to_file_row(
+            file_path.clone(),
+            entry.hash.clone(),
+            entry.last_modified
+        );
+        new_file_row.set_mode(entry.mode);
+        new_file_row.set_symlink(as_link);
+        sync::note_disk(&mut new_file_row, &entry);
+
+        db::insert_file(conn, &new_file_row, root_dir).unwrap_or_else(|e| {
+            eprintln!("Failed to insert new: {:?}", e);
+        });
+    }
+
+    sync::queue_op(conn, &file_path, OpKind::Upload, root_dir);
+    true
+}
+
+// Carry a rename within the tree over to the DB and server, returning whether there was anything tracked to move
+async fn rename_path(from: &Path, to: &Path, watch_root: &Path, conn: &Connection, init_dir: &Path, rules: &IgnoreRules, root_dir: &String) -> bool {
+    let (Some(from_path), Some(to_path)) = (sync::tracked_path(from, watch_root, init_dir), sync::tracked_path(to, watch_root, init_dir)) else {
+        return false;
+    };
+
+    // Renamed to a name that isn't synced, which is as good as deleted
+    if (to.is_file() && !utils::check_file_path(&to.to_path_buf())) || rules.is_ignored(to, to.is_dir()) {
+        sync::forget_path(conn, &from_path, root_dir);
+        return true;
+    }
+
+    sync::move_path(conn, &from_path, &to_path, root_dir).await
+}
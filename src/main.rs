@@ -6,6 +6,7 @@ use std::path::PathBuf;
 use dotenv::dotenv;
 use clap::{ Parser, Subcommand };
 use crate::client::apis;
+use crate::shared::models::{ConflictPolicy, SelectiveMode, SymlinkPolicy};
 
 // Commands
 #[derive(Parser, Debug)]
@@ -50,11 +51,93 @@ enum Commands {
         url: String,
     },
 
+    SetConflictPolicy {
+        #[arg(long, value_enum)]
+        policy: ConflictPolicy,
+    },
+
+    SetUploadConcurrency {
+        #[arg(long)]
+        concurrency: usize,
+    },
+
+    SetSymlinkPolicy {
+        #[arg(long, value_enum)]
+        policy: SymlinkPolicy,
+    },
+
+    SetRescanInterval {
+        #[arg(long)]
+        seconds: u64,
+    },
+
+    SetAllowExternalLinks {
+        #[arg(long, action = clap::ArgAction::Set)]
+        allow: bool,
+    },
+
     Start {
         #[arg(long)]
         path: String
     },
-    Refresh
+    Refresh,
+
+    History {
+        #[arg(long)]
+        path: String
+    },
+
+    Restore {
+        #[arg(long)]
+        path: String,
+
+        #[arg(long)]
+        version: i64,
+    },
+
+    SelectiveSync {
+        #[command(subcommand)]
+        command: SelectiveSyncCommands
+    },
+
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands
+    },
+}
+
+// Choose which remote folders are kept on this device
+#[derive(Subcommand, Debug)]
+enum SelectiveSyncCommands {
+    Exclude {
+        #[arg(long)]
+        path: String
+    },
+
+    Include {
+        #[arg(long)]
+        path: String
+    },
+
+    Remove {
+        #[arg(long)]
+        path: String
+    },
+
+    List,
+}
+
+// Deleted files the server keeps until they expire
+#[derive(Subcommand, Debug)]
+enum TrashCommands {
+    List,
+
+    Restore {
+        #[arg(long)]
+        id: i64,
+    },
+
+    Empty,
 }
 
 #[tokio::main]
@@ -105,6 +188,102 @@ async fn main() {
                         }
                     }
                 }
+                Commands::History { path } => {
+                    match apis::file::list_versions(&path).await {
+                        Ok(versions) => {
+                            if versions.is_empty() {
+                                println!("No previous versions of {}", path);
+                            }
+
+                            for version in versions {
+                                println!(
+                                    "Version {}: hash {}, modified {}, archived {}",
+                                    version.version(),
+                                    version.hash(),
+                                    version.last_modified().to_rfc3339(),
+                                    version.archived_at().to_rfc3339()
+                                );
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Error fetching history, {}", e);
+                        }
+                    }
+                }
+
+                Commands::Restore { path, version } => {
+                    match apis::file::restore_version(&path, version).await {
+                        Ok(file) => {
+                            println!("Restored {} to version {} ({})", file.path(), version, file.hash());
+                        }
+                        Err(e) => {
+                            eprintln!("Error restoring version, {}", e);
+                        }
+                    }
+                }
+
+                Commands::SelectiveSync { command } => {
+                    let result = match command {
+                        SelectiveSyncCommands::Exclude { path } => client::set_selective_rule(&path, SelectiveMode::Exclude),
+                        SelectiveSyncCommands::Include { path } => client::set_selective_rule(&path, SelectiveMode::Include),
+                        SelectiveSyncCommands::Remove { path } => client::remove_selective_rule(&path),
+                        SelectiveSyncCommands::List => client::list_selective_rules(),
+                    };
+
+                    if let Err(e) = result {
+                        eprintln!("Error updating selective sync, {}", e);
+                    }
+                }
+
+                Commands::Trash { command } => {
+                    match command {
+                        TrashCommands::List => {
+                            match apis::file::list_trash().await {
+                                Ok(trashed) => {
+                                    if trashed.is_empty() {
+                                        println!("Trash is empty");
+                                    }
+
+                                    for trashed_file in trashed {
+                                        println!(
+                                            "{}: {}, hash {}, deleted {}",
+                                            trashed_file.id(),
+                                            trashed_file.path(),
+                                            trashed_file.hash(),
+                                            trashed_file.deleted_at().to_rfc3339()
+                                        );
+                                    }
+                                }
+                                Err(e) => {
+                                    eprintln!("Error fetching trash, {}", e);
+                                }
+                            }
+                        }
+
+                        TrashCommands::Restore { id } => {
+                            match apis::file::restore_trashed(id).await {
+                                Ok(file) => {
+                                    println!("Restored {} ({})", file.path(), file.hash());
+                                }
+                                Err(e) => {
+                                    eprintln!("Error restoring file, {}", e);
+                                }
+                            }
+                        }
+
+                        TrashCommands::Empty => {
+                            match apis::file::purge_trash().await {
+                                Ok(purged) => {
+                                    println!("Permanently deleted {} files", purged);
+                                }
+                                Err(e) => {
+                                    eprintln!("Error emptying trash, {}", e);
+                                }
+                            }
+                        }
+                    }
+                }
+
                 Commands::SetUrl { url } => {
                     match client::save_url(url.as_str()).await {
                         Ok(_) => {}
@@ -113,6 +292,51 @@ async fn main() {
                         }
                     }
                 }
+
+                Commands::SetConflictPolicy { policy } => {
+                    match client::save_conflict_policy(policy).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Error saving conflict policy, {}", e);
+                        }
+                    }
+                }
+
+                Commands::SetUploadConcurrency { concurrency } => {
+                    match client::save_upload_concurrency(concurrency).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Error saving upload concurrency, {}", e);
+                        }
+                    }
+                }
+
+                Commands::SetSymlinkPolicy { policy } => {
+                    match client::save_symlink_policy(policy).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Error saving symlink policy, {}", e);
+                        }
+                    }
+                }
+
+                Commands::SetRescanInterval { seconds } => {
+                    match client::save_rescan_interval(seconds).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Error saving rescan interval, {}", e);
+                        }
+                    }
+                }
+
+                Commands::SetAllowExternalLinks { allow } => {
+                    match client::save_allow_external_links(allow).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("Error saving external links setting, {}", e);
+                        }
+                    }
+                }
             }
         }
     }
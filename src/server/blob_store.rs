@@ -0,0 +1,151 @@
+// Content addressed storage for uploaded file contents, keyed by BLAKE3 hash
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use actix_web::web::Bytes;
+use chrono::{TimeDelta, Utc};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use rusqlite::Connection;
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+use crate::server::db;
+use crate::shared::errors::DbError;
+
+const BLOB_ROOT: &str = "blobs";
+
+// Unreferenced blobs are kept this long, giving chunk uploads time to be committed
+const GC_GRACE_PERIOD: TimeDelta = TimeDelta::hours(24);
+
+// Unfinished upload sessions are resumable for this long after their last activity
+const SESSION_EXPIRY: TimeDelta = TimeDelta::days(7);
+
+pub fn init() -> io::Result<()> {
+    std::fs::create_dir_all(PathBuf::from(BLOB_ROOT).join("tmp"))?;
+    std::fs::create_dir_all(PathBuf::from(BLOB_ROOT).join("sessions"))
+}
+
+// Blobs are fanned out into directories by the first two characters of their hash
+pub fn blob_path(hash: &str) -> PathBuf {
+    PathBuf::from(BLOB_ROOT).join(hash.get(..2).unwrap_or("00")).join(hash)
+}
+
+// Unique path to stage incoming data at before its hash is known
+pub fn temp_path() -> PathBuf {
+    PathBuf::from(BLOB_ROOT).join("tmp").join(Uuid::new_v4().to_string())
+}
+
+// Bytes received so far for an upload session
+pub fn session_path(id: &str) -> PathBuf {
+    PathBuf::from(BLOB_ROOT).join("sessions").join(id)
+}
+
+// Drop a session along with the data it has received
+pub fn remove_session(conn: &Connection, id: &str) -> Result<(), Box<dyn Error>> {
+    match std::fs::remove_file(session_path(id)) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Box::new(e)),
+    }
+
+    db::remove_session(conn, id)?;
+    Ok(())
+}
+
+// Drop upload sessions nobody has touched in a while
+pub fn expire_sessions(conn: &Connection) -> Result<usize, Box<dyn Error>> {
+    let ids = db::get_expired_sessions(conn, Utc::now() - SESSION_EXPIRY)?;
+
+    for id in &ids {
+        remove_session(conn, id)?;
+    }
+
+    Ok(ids.len())
+}
+
+// Files the contents of a blob are read from in order, its chunks if it was uploaded as a manifest
+pub fn content_paths(conn: &Connection, hash: &str) -> Result<Vec<PathBuf>, DbError> {
+    let chunks = db::get_manifest(conn, hash)?;
+
+    if chunks.is_empty() {
+        return Ok(vec![blob_path(hash)]);
+    }
+
+    Ok(chunks.iter().map(|chunk| blob_path(chunk.hash())).collect())
+}
+
+// Stream the files backing a blob one after another
+pub fn stream(paths: Vec<PathBuf>) -> impl Stream<Item = io::Result<Bytes>> {
+    futures_util::stream::iter(paths)
+        .then(fs::File::open)
+        .map_ok(ReaderStream::new)
+        .try_flatten()
+}
+
+// Move a staged file into the store under its hash, dropping it if that content is already stored
+pub fn ingest(conn: &Connection, staged: &Path, hash: &str) -> Result<(), Box<dyn Error>> {
+    let path = blob_path(hash);
+    let size = std::fs::metadata(staged)?.len();
+
+    if path.exists() {
+        std::fs::remove_file(staged)?;
+    } else {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(staged, &path)?;
+    }
+
+    db::insert_blob(conn, hash, size)?;
+    Ok(())
+}
+
+// Delete blobs that no file, version or manifest refers to any more
+pub fn collect_garbage(conn: &Connection) -> Result<usize, Box<dyn Error>> {
+    let mut removed = 0;
+
+    // Dropping a manifest can leave its chunks unreferenced, so go until nothing more is freed
+    loop {
+        let hashes = db::get_unreferenced_blobs(conn, Utc::now() - GC_GRACE_PERIOD)?;
+        if hashes.is_empty() {
+            return Ok(removed);
+        }
+
+        for hash in &hashes {
+            db::remove_manifest(conn, hash)?;
+
+            match std::fs::remove_file(blob_path(hash)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+
+            db::remove_blob(conn, hash)?;
+        }
+
+        removed += hashes.len();
+    }
+}
+
+// Move contents stored under uploads/ and versions/ before the blob store existed into it
+pub fn migrate_legacy(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    for (path, hash) in db::get_unstored_files(conn)? {
+        let legacy_path = PathBuf::from(&path);
+        if legacy_path.is_file() {
+            ingest(conn, &legacy_path, &hash)?;
+        }
+    }
+
+    for (path, version, hash) in db::get_unstored_versions(conn)? {
+        let legacy_path = PathBuf::from("versions")
+            .join(path.strip_prefix("uploads/").unwrap_or(&path))
+            .join(version.to_string());
+        if legacy_path.is_file() {
+            ingest(conn, &legacy_path, &hash)?;
+        }
+    }
+
+    // Counts are derived from the files and versions tables, so rebuilding them also repairs drift
+    db::recount_blob_refs(conn)?;
+    Ok(())
+}
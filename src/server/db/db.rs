@@ -1,8 +1,8 @@
 use std::path::Path;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection};
 use crate::shared::errors::DbError;
-use crate::shared::models::{FileRow, UserRow};
+use crate::shared::models::{ChunkInfo, FileChange, FileEvent, FileEventKind, FileRow, FileVersion, SessionRow, TrashedFile, UserRow};
 use crate::shared::utils;
 use argon2::{password_hash::{
     SaltString,
@@ -24,6 +24,137 @@ pub fn init_db() -> Result<Connection, DbError> {
         params![],
     )?;
 
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_versions(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            last_modified TEXT NOT NULL,
+            archived_at TEXT NOT NULL,
+            username TEXT NOT NULL,
+            UNIQUE(path, version)
+        );",
+        params![],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blobs(
+            hash TEXT PRIMARY KEY,
+            size INTEGER NOT NULL,
+            ref_count INTEGER NOT NULL DEFAULT 0
+        );",
+        params![],
+    )?;
+
+    // Permission bits, so files come back with the ones they were uploaded with
+    utils::ensure_column(&conn, "files", "mode", "INTEGER")?;
+    // Symbolic links synced as links, their contents being the target
+    utils::ensure_column(&conn, "files", "symlink", "INTEGER NOT NULL DEFAULT 0")?;
+    utils::ensure_column(&conn, "file_versions", "symlink", "INTEGER NOT NULL DEFAULT 0")?;
+    utils::ensure_column(&conn, "file_versions", "mode", "INTEGER")?;
+
+    // When a blob was last stored or asked for, so chunks awaiting a commit survive garbage collection
+    utils::ensure_column(&conn, "blobs", "stored_at", "TEXT")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS manifest_chunks(
+            hash TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            chunk_hash TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            PRIMARY KEY(hash, position)
+        );",
+        params![],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS upload_sessions(
+            id TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            last_modified TEXT NOT NULL,
+            base_hash TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            username TEXT NOT NULL
+        );",
+        params![],
+    )?;
+
+    utils::ensure_column(&conn, "upload_sessions", "mode", "INTEGER")?;
+
+    // Every upload and delete in order, so clients can ask for what changed since they last looked
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS changes(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            hash TEXT,
+            changed_at TEXT NOT NULL,
+            username TEXT NOT NULL
+        );",
+        params![],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS changes_by_user ON changes(username, id);",
+        params![],
+    )?;
+
+    // Where moved files came from
+    utils::ensure_column(&conn, "changes", "from_path", "TEXT")?;
+    utils::ensure_column(&conn, "changes", "mode", "INTEGER")?;
+
+    // Directories are tracked on their own so empty ones are kept too
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS directories(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL,
+            username TEXT NOT NULL
+        );",
+        params![],
+    )?;
+
+    // Deleted files, still holding their contents until they are restored or purged
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS trash(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            last_modified TEXT NOT NULL,
+            deleted_at TEXT NOT NULL,
+            username TEXT NOT NULL
+        );",
+        params![],
+    )?;
+
+    utils::ensure_column(&conn, "trash", "mode", "INTEGER")?;
+    utils::ensure_column(&conn, "trash", "symlink", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Which users have stored each chunk, so nobody can build a file out of chunks they never had
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blob_owners(
+            hash TEXT NOT NULL,
+            username TEXT NOT NULL,
+            PRIMARY KEY(hash, username)
+        );",
+        params![],
+    )?;
+
+    // Chunks of files committed before owners were tracked belong to whoever holds those files
+    conn.execute(
+        "INSERT OR IGNORE INTO blob_owners(hash, username)
+            SELECT manifest_chunks.chunk_hash, holders.username FROM manifest_chunks
+            JOIN (
+                SELECT hash, username FROM files
+                UNION SELECT hash, username FROM file_versions
+                UNION SELECT hash, username FROM trash
+            ) AS holders ON holders.hash = manifest_chunks.hash",
+        params![],
+    )?;
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users(
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -37,18 +168,20 @@ pub fn init_db() -> Result<Connection, DbError> {
 }
 
 pub fn get_files(conn: &Connection, username: &String) -> Result<Vec<FileRow>, DbError> {
-    let mut statement = conn.prepare("SELECT path, hash, last_modified FROM files WHERE username=?1")?;
+    let mut statement = conn.prepare("SELECT path, hash, last_modified, mode, symlink FROM files WHERE username=?1")?;
 
     let mut rows = statement.query(params![username])?;
     let mut files: Vec<FileRow> = Vec::new();
 
     while let Some(row) = rows.next()? {
         let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
-        let file_row = utils::convert_to_file_row(
+        let mut file_row = utils::convert_to_file_row(
             row.get(0)?,
             row.get(1)?,
             last_modified.to_utc()
         );
+        file_row.set_mode(row.get(3)?);
+        file_row.set_symlink(row.get(4)?);
         files.push(file_row);
     };
 
@@ -58,7 +191,7 @@ pub fn get_files(conn: &Connection, username: &String) -> Result<Vec<FileRow>, D
 
 pub fn get_file(conn: &Connection, path: &String, username: &String) -> Result<Vec<FileRow>, DbError> {
     let mut statement = conn.prepare(
-        "SELECT path, hash, last_modified FROM files WHERE path=?1 AND username=?2"
+        "SELECT path, hash, last_modified, mode, symlink FROM files WHERE path=?1 AND username=?2"
     )?;
 
     let mut rows = statement.query(params![path, username])?;
@@ -67,11 +200,13 @@ pub fn get_file(conn: &Connection, path: &String, username: &String) -> Result<V
     while let Some(row) = rows.next()? {
         // Converts database string rfc time to DateTime object
         let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
-        let file_row: FileRow = utils::convert_to_file_row(
+        let mut file_row: FileRow = utils::convert_to_file_row(
             row.get(0)?,
             row.get(1)?,
             last_modified.to_utc()
         );
+        file_row.set_mode(row.get(3)?);
+        file_row.set_symlink(row.get(4)?);
 
         file_rows.push(file_row);
     }
@@ -79,26 +214,592 @@ pub fn get_file(conn: &Connection, path: &String, username: &String) -> Result<V
     Ok(file_rows)
 }
 
+pub fn get_files_under(conn: &Connection, path: &str, username: &String) -> Result<Vec<FileRow>, DbError> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let mut statement = conn.prepare(
+        "SELECT path, hash, last_modified, mode, symlink FROM files WHERE substr(path, 1, length(?1))=?1 AND username=?2"
+    )?;
+
+    let mut rows = statement.query(params![prefix, username])?;
+    let mut files: Vec<FileRow> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
+        let mut file_row = utils::convert_to_file_row(
+            row.get(0)?,
+            row.get(1)?,
+            last_modified.to_utc()
+        );
+        file_row.set_mode(row.get(3)?);
+        file_row.set_symlink(row.get(4)?);
+        files.push(file_row);
+    }
+
+    Ok(files)
+}
+
+// Every files and file_versions row holds one reference on the blob its hash names
 pub fn insert_file(conn: &Connection, file: &FileRow, username: &String) -> Result<(), DbError> {
     let mut statement = conn.prepare(
-        "INSERT INTO files(path, hash, last_modified, username)\
-            VALUES (?1, ?2, ?3, ?4)"
+        "INSERT INTO files(path, hash, last_modified, username, mode, symlink)\
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
     )?;
 
-    statement.execute(params![file.path(), file.hash(), file.last_modified().to_rfc3339(), username])?;
+    statement.execute(params![file.path(), file.hash(), file.last_modified().to_rfc3339(), username, file.mode(), file.is_symlink()])?;
+    add_blob_refs(conn, file.hash(), 1)?;
+    Ok(())
+}
+
+pub fn update_file(conn: &Connection, file: &FileRow, username: &String) -> Result<(), DbError> {
+    let previous_hash: String = conn.query_row(
+        "SELECT hash FROM files WHERE path=?1 AND username=?2",
+        params![file.path(), username],
+        |row| row.get(0),
+    )?;
+
+    let mut statement = conn.prepare(
+        "UPDATE files SET hash=?1, last_modified=?2, mode=?3, symlink=?4 WHERE path=?5 AND username=?6"
+    )?;
+
+    statement.execute(params![file.hash(), file.last_modified().to_rfc3339(), file.mode(), file.is_symlink(), file.path(), username])?;
+    add_blob_refs(conn, file.hash(), 1)?;
+    add_blob_refs(conn, &previous_hash, -1)?;
     Ok(())
 }
 
 pub fn remove_file(conn: &Connection, path: &String, username: &String) -> Result<(), DbError> {
+    let hashes = conn.prepare("SELECT hash FROM files WHERE path=?1 AND username=?2")?
+        .query_map(params![path, username], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
     let mut statement = conn.prepare(
         "DELETE FROM files WHERE path=?1 AND username=?2"
     )?;
 
     statement.execute(params![path, username])?;
 
+    for hash in hashes {
+        add_blob_refs(conn, &hash, -1)?;
+    }
+
+    Ok(())
+}
+
+// Give a file and its history a new path, keeping its contents
+pub fn move_file(conn: &Connection, from: &String, to: &String, username: &String) -> Result<(), DbError> {
+    conn.execute(
+        "UPDATE files SET path=?1 WHERE path=?2 AND username=?3",
+        params![to, from, username],
+    )?;
+
+    // History left behind by an earlier file at the destination keeps its version numbers
+    let offset = next_version(conn, to, username)? - 1;
+    conn.execute(
+        "UPDATE file_versions SET path=?1, version=version+?2 WHERE path=?3 AND username=?4",
+        params![to, offset, from, username],
+    )?;
+
     Ok(())
 }
 
+// Returns whether the directory wasn't already there
+pub fn insert_directory(conn: &Connection, path: &String, username: &String) -> Result<bool, DbError> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO directories(path, created_at, username) VALUES (?1, ?2, ?3)",
+        params![path, Utc::now().to_rfc3339(), username],
+    )?;
+
+    Ok(inserted > 0)
+}
+
+pub fn directory_exists(conn: &Connection, path: &String, username: &String) -> Result<bool, DbError> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM directories WHERE path=?1 AND username=?2",
+        params![path, username],
+        |row| row.get(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+pub fn get_directories(conn: &Connection, username: &String) -> Result<Vec<String>, DbError> {
+    let paths = conn.prepare("SELECT path FROM directories WHERE username=?1 ORDER BY path")?
+        .query_map(params![username], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(paths)
+}
+
+// A directory and every directory inside it, deepest first
+pub fn get_directories_under(conn: &Connection, path: &str, username: &String) -> Result<Vec<String>, DbError> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let paths = conn.prepare(
+        "SELECT path FROM directories WHERE (path=?1 OR substr(path, 1, length(?2))=?2) AND username=?3 ORDER BY length(path) DESC"
+    )?
+        .query_map(params![path, prefix, username], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(paths)
+}
+
+pub fn remove_directory(conn: &Connection, path: &String, username: &String) -> Result<(), DbError> {
+    conn.execute("DELETE FROM directories WHERE path=?1 AND username=?2", params![path, username])?;
+
+    Ok(())
+}
+
+pub fn move_directory(conn: &Connection, from: &String, to: &String, username: &String) -> Result<(), DbError> {
+    conn.execute("UPDATE directories SET path=?1 WHERE path=?2 AND username=?3", params![to, from, username])?;
+
+    Ok(())
+}
+
+// Move a file's row into the trash, its contents stay referenced
+pub fn trash_file(conn: &Connection, file: &FileRow, username: &String) -> Result<(), DbError> {
+    conn.execute(
+        "INSERT INTO trash(path, hash, last_modified, deleted_at, username, mode, symlink) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![file.path(), file.hash(), file.last_modified().to_rfc3339(), Utc::now().to_rfc3339(), username, file.mode(), file.is_symlink()],
+    )?;
+    add_blob_refs(conn, file.hash(), 1)?;
+
+    remove_file(conn, &file.path().to_string(), username)
+}
+
+pub fn get_trash(conn: &Connection, username: &String) -> Result<Vec<TrashedFile>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT id, path, hash, last_modified, deleted_at, mode, symlink FROM trash WHERE username=?1 ORDER BY id DESC"
+    )?;
+
+    let mut rows = statement.query(params![username])?;
+    let mut trashed: Vec<TrashedFile> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        trashed.push(convert_to_trashed_file(row)?);
+    }
+
+    Ok(trashed)
+}
+
+pub fn get_trashed(conn: &Connection, id: i64, username: &String) -> Result<Vec<TrashedFile>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT id, path, hash, last_modified, deleted_at, mode, symlink FROM trash WHERE id=?1 AND username=?2"
+    )?;
+
+    let mut rows = statement.query(params![id, username])?;
+    let mut trashed: Vec<TrashedFile> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        trashed.push(convert_to_trashed_file(row)?);
+    }
+
+    Ok(trashed)
+}
+
+// Drop trashed files for good, leaving their contents to blob garbage collection
+pub fn remove_trashed(conn: &Connection, ids: &[i64]) -> Result<usize, DbError> {
+    let mut removed = 0;
+
+    for id in ids {
+        let hashes = conn.prepare("SELECT hash FROM trash WHERE id=?1")?
+            .query_map(params![id], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        removed += conn.execute("DELETE FROM trash WHERE id=?1", params![id])?;
+
+        for hash in hashes {
+            add_blob_refs(conn, &hash, -1)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+pub fn get_trash_ids(conn: &Connection, username: &String) -> Result<Vec<i64>, DbError> {
+    let ids = conn.prepare("SELECT id FROM trash WHERE username=?1")?
+        .query_map(params![username], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    Ok(ids)
+}
+
+pub fn get_expired_trash(conn: &Connection, deleted_before: DateTime<Utc>) -> Result<Vec<i64>, DbError> {
+    let ids = conn.prepare("SELECT id FROM trash WHERE deleted_at < ?1")?
+        .query_map(params![deleted_before.to_rfc3339()], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    Ok(ids)
+}
+
+fn convert_to_trashed_file(row: &rusqlite::Row) -> Result<TrashedFile, DbError> {
+    let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(3)?)?;
+    let deleted_at = DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)?;
+
+    let mut trashed_file = TrashedFile::new(
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        last_modified.to_utc(),
+        deleted_at.to_utc()
+    );
+    trashed_file.set_mode(row.get(5)?);
+    trashed_file.set_symlink(row.get(6)?);
+
+    Ok(trashed_file)
+}
+
+pub fn next_version(conn: &Connection, path: &String, username: &String) -> Result<i64, DbError> {
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) + 1 FROM file_versions WHERE path=?1 AND username=?2",
+        params![path, username],
+        |row| row.get(0),
+    )?;
+
+    Ok(version)
+}
+
+pub fn insert_version(conn: &Connection, file: &FileRow, version: i64, username: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO file_versions(path, version, hash, last_modified, archived_at, username, symlink, mode)\
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+    )?;
+
+    statement.execute(params![
+        file.path(),
+        version,
+        file.hash(),
+        file.last_modified().to_rfc3339(),
+        Utc::now().to_rfc3339(),
+        username,
+        file.is_symlink(),
+        file.mode()
+    ])?;
+    add_blob_refs(conn, file.hash(), 1)?;
+    Ok(())
+}
+
+pub fn get_versions(conn: &Connection, path: &String, username: &String) -> Result<Vec<FileVersion>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT version, hash, last_modified, archived_at, symlink, mode FROM file_versions WHERE path=?1 AND username=?2 ORDER BY version DESC"
+    )?;
+
+    let mut rows = statement.query(params![path, username])?;
+    let mut versions: Vec<FileVersion> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        versions.push(convert_to_file_version(row)?);
+    }
+
+    Ok(versions)
+}
+
+pub fn get_version(conn: &Connection, path: &String, version: i64, username: &String) -> Result<Vec<FileVersion>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT version, hash, last_modified, archived_at, symlink, mode FROM file_versions WHERE path=?1 AND version=?2 AND username=?3"
+    )?;
+
+    let mut rows = statement.query(params![path, version, username])?;
+    let mut versions: Vec<FileVersion> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        versions.push(convert_to_file_version(row)?);
+    }
+
+    Ok(versions)
+}
+
+fn convert_to_file_version(row: &rusqlite::Row) -> Result<FileVersion, DbError> {
+    let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)?;
+    let archived_at = DateTime::parse_from_rfc3339(&row.get::<_, String>(3)?)?;
+
+    let mut file_version = FileVersion::new(
+        row.get(0)?,
+        row.get(1)?,
+        last_modified.to_utc(),
+        archived_at.to_utc()
+    );
+    file_version.set_symlink(row.get(4)?);
+    file_version.set_mode(row.get(5)?);
+
+    Ok(file_version)
+}
+
+pub fn insert_blob(conn: &Connection, hash: &str, size: u64) -> Result<(), DbError> {
+    conn.execute(
+        "INSERT INTO blobs(hash, size, ref_count, stored_at) VALUES (?1, ?2, 0, ?3)
+            ON CONFLICT(hash) DO UPDATE SET stored_at=excluded.stored_at",
+        params![hash, size as i64, Utc::now().to_rfc3339()],
+    )?;
+
+    Ok(())
+}
+
+pub fn add_blob_owner(conn: &Connection, hash: &str, username: &String) -> Result<(), DbError> {
+    conn.execute(
+        "INSERT OR IGNORE INTO blob_owners(hash, username) VALUES (?1, ?2)",
+        params![hash, username],
+    )?;
+
+    Ok(())
+}
+
+// Hashes the user hasn't stored yet, touching the ones they have so they outlive the upload.
+// Blobs only another user stored count as missing, so this never says what anyone else has
+pub fn get_missing_blobs(conn: &Connection, hashes: &[String], username: &String) -> Result<Vec<String>, DbError> {
+    let mut statement = conn.prepare(
+        "UPDATE blobs SET stored_at=?1 WHERE hash=?2
+            AND EXISTS (SELECT 1 FROM blob_owners WHERE blob_owners.hash=?2 AND blob_owners.username=?3)"
+    )?;
+    let now = Utc::now().to_rfc3339();
+    let mut missing = Vec::new();
+
+    for hash in hashes {
+        if statement.execute(params![now, hash, username])? == 0 {
+            missing.push(hash.clone());
+        }
+    }
+
+    Ok(missing)
+}
+
+// A chunked file is a blob row for the whole file plus a manifest holding one reference per chunk
+pub fn insert_manifest(conn: &Connection, hash: &str, chunks: &[ChunkInfo]) -> Result<(), DbError> {
+    insert_blob(conn, hash, chunks.iter().map(|chunk| chunk.size()).sum())?;
+
+    if !get_manifest(conn, hash)?.is_empty() {
+        return Ok(());
+    }
+
+    let mut statement = conn.prepare(
+        "INSERT INTO manifest_chunks(hash, position, chunk_hash, size) VALUES (?1, ?2, ?3, ?4)"
+    )?;
+
+    for (position, chunk) in chunks.iter().enumerate() {
+        statement.execute(params![hash, position as i64, chunk.hash(), chunk.size() as i64])?;
+        add_blob_refs(conn, chunk.hash(), 1)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_manifest(conn: &Connection, hash: &str) -> Result<Vec<ChunkInfo>, DbError> {
+    let chunks = conn.prepare("SELECT chunk_hash, size FROM manifest_chunks WHERE hash=?1 ORDER BY position")?
+        .query_map(params![hash], |row| Ok(ChunkInfo::new(row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+        .collect::<Result<Vec<ChunkInfo>, _>>()?;
+
+    Ok(chunks)
+}
+
+pub fn remove_manifest(conn: &Connection, hash: &str) -> Result<(), DbError> {
+    for chunk in get_manifest(conn, hash)? {
+        add_blob_refs(conn, chunk.hash(), -1)?;
+    }
+
+    conn.execute("DELETE FROM manifest_chunks WHERE hash=?1", params![hash])?;
+
+    Ok(())
+}
+
+fn add_blob_refs(conn: &Connection, hash: &str, delta: i64) -> Result<(), DbError> {
+    conn.execute(
+        "UPDATE blobs SET ref_count = ref_count + ?1 WHERE hash=?2",
+        params![delta, hash],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_unreferenced_blobs(conn: &Connection, stored_before: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+    let hashes = conn.prepare("SELECT hash FROM blobs WHERE ref_count <= 0 AND (stored_at IS NULL OR stored_at < ?1)")?
+        .query_map(params![stored_before.to_rfc3339()], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(hashes)
+}
+
+pub fn remove_blob(conn: &Connection, hash: &str) -> Result<(), DbError> {
+    conn.execute("DELETE FROM blobs WHERE hash=?1", params![hash])?;
+    conn.execute("DELETE FROM blob_owners WHERE hash=?1", params![hash])?;
+
+    Ok(())
+}
+
+pub fn recount_blob_refs(conn: &Connection) -> Result<(), DbError> {
+    conn.execute(
+        "UPDATE blobs SET ref_count =
+            (SELECT COUNT(*) FROM files WHERE files.hash = blobs.hash) +
+            (SELECT COUNT(*) FROM file_versions WHERE file_versions.hash = blobs.hash) +
+            (SELECT COUNT(*) FROM trash WHERE trash.hash = blobs.hash) +
+            (SELECT COUNT(*) FROM manifest_chunks WHERE manifest_chunks.chunk_hash = blobs.hash)",
+        params![],
+    )?;
+
+    Ok(())
+}
+
+// Files and versions whose contents haven't been moved into the blob store yet
+pub fn get_unstored_files(conn: &Connection) -> Result<Vec<(String, String)>, DbError> {
+    let files = conn.prepare("SELECT path, hash FROM files WHERE hash NOT IN (SELECT hash FROM blobs)")?
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+
+    Ok(files)
+}
+
+pub fn get_unstored_versions(conn: &Connection) -> Result<Vec<(String, i64, String)>, DbError> {
+    let versions = conn.prepare("SELECT path, version, hash FROM file_versions WHERE hash NOT IN (SELECT hash FROM blobs)")?
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, i64, String)>, _>>()?;
+
+    Ok(versions)
+}
+
+pub fn insert_session(conn: &Connection, session: &SessionRow, username: &String) -> Result<(), DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO upload_sessions(id, path, hash, size, last_modified, base_hash, updated_at, username, mode)\
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+    )?;
+
+    statement.execute(params![
+        session.id(),
+        session.path(),
+        session.hash(),
+        session.size() as i64,
+        session.last_modified().to_rfc3339(),
+        session.base_hash(),
+        Utc::now().to_rfc3339(),
+        username,
+        session.mode()
+    ])?;
+    Ok(())
+}
+
+// Mark a session as active, keeping it from expiring
+pub fn touch_session(conn: &Connection, id: &str) -> Result<(), DbError> {
+    conn.execute(
+        "UPDATE upload_sessions SET updated_at=?1 WHERE id=?2",
+        params![Utc::now().to_rfc3339(), id],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_session(conn: &Connection, id: &str, username: &String) -> Result<Vec<SessionRow>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT id, path, hash, size, last_modified, base_hash, mode FROM upload_sessions WHERE id=?1 AND username=?2"
+    )?;
+
+    let mut rows = statement.query(params![id, username])?;
+    let mut sessions: Vec<SessionRow> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        sessions.push(convert_to_session_row(row)?);
+    }
+
+    Ok(sessions)
+}
+
+// Open sessions for a path, whatever contents they were uploading
+pub fn get_path_sessions(conn: &Connection, path: &String, username: &String) -> Result<Vec<SessionRow>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT id, path, hash, size, last_modified, base_hash, mode FROM upload_sessions WHERE path=?1 AND username=?2"
+    )?;
+
+    let mut rows = statement.query(params![path, username])?;
+    let mut sessions: Vec<SessionRow> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        sessions.push(convert_to_session_row(row)?);
+    }
+
+    Ok(sessions)
+}
+
+pub fn get_expired_sessions(conn: &Connection, updated_before: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+    let ids = conn.prepare("SELECT id FROM upload_sessions WHERE updated_at < ?1")?
+        .query_map(params![updated_before.to_rfc3339()], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(ids)
+}
+
+pub fn remove_session(conn: &Connection, id: &str) -> Result<(), DbError> {
+    conn.execute("DELETE FROM upload_sessions WHERE id=?1", params![id])?;
+
+    Ok(())
+}
+
+fn convert_to_session_row(row: &rusqlite::Row) -> Result<SessionRow, DbError> {
+    let last_modified = DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)?;
+
+    let mut session = SessionRow::new(
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get::<_, i64>(3)? as u64,
+        last_modified.to_utc(),
+        row.get(5)?
+    );
+    session.set_mode(row.get(6)?);
+
+    Ok(session)
+}
+
+// Append a change to the journal, returning its cursor
+pub fn insert_change(conn: &Connection, event: &FileEvent, username: &String) -> Result<i64, DbError> {
+    let mut statement = conn.prepare(
+        "INSERT INTO changes(path, kind, hash, changed_at, username, from_path, mode) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+    )?;
+
+    statement.execute(params![
+        event.path(),
+        event.kind().as_str(),
+        event.hash(),
+        Utc::now().to_rfc3339(),
+        username,
+        event.from(),
+        event.mode()
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Changes after a cursor, oldest first
+pub fn get_changes(conn: &Connection, since: i64, limit: usize, username: &String) -> Result<Vec<FileChange>, DbError> {
+    let mut statement = conn.prepare(
+        "SELECT id, path, kind, hash, from_path, mode FROM changes WHERE username=?1 AND id>?2 ORDER BY id LIMIT ?3"
+    )?;
+
+    let mut rows = statement.query(params![username, since, limit as i64])?;
+    let mut changes: Vec<FileChange> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let kind = match FileEventKind::parse(&row.get::<_, String>(2)?) {
+            Some(kind) => kind,
+            None => return Err(DbError::Custom(String::from("Unknown change kind")))
+        };
+
+        let mut event = match (kind, row.get::<_, Option<String>>(4)?, row.get::<_, Option<String>>(3)?) {
+            (FileEventKind::Moved, Some(from), Some(hash)) => FileEvent::moved(from, row.get(1)?, hash),
+            (kind, _, hash) => FileEvent::new(kind, row.get(1)?, hash),
+        };
+        event.set_mode(row.get(5)?);
+
+        changes.push(FileChange::new(row.get(0)?, event));
+    }
+
+    Ok(changes)
+}
+
+// Cursor of the newest change, 0 before anything has changed
+pub fn latest_change(conn: &Connection, username: &String) -> Result<i64, DbError> {
+    let cursor = conn.query_row(
+        "SELECT COALESCE(MAX(id), 0) FROM changes WHERE username=?1",
+        params![username],
+        |row| row.get(0)
+    )?;
+
+    Ok(cursor)
+}
+
 pub fn register_user(conn: &Connection, username: &String, password: &String) -> Result<(), DbError> {
     let salt_string = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
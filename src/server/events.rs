@@ -0,0 +1,29 @@
+// Fans committed file changes out to each user's /events subscribers
+use tokio::sync::broadcast;
+use crate::shared::models::FileEvent;
+
+// Events a slow subscriber can fall behind by before it is told to resync
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Clone)]
+pub struct EventBroadcaster {
+    sender: broadcast::Sender<(String, FileEvent)>,
+}
+
+impl Default for EventBroadcaster {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBroadcaster { sender }
+    }
+}
+
+impl EventBroadcaster {
+    pub fn publish(&self, username: &str, event: FileEvent) {
+        // Sending only fails when nobody is subscribed, which is fine
+        self.sender.send((username.to_string(), event)).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, FileEvent)> {
+        self.sender.subscribe()
+    }
+}
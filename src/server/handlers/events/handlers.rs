@@ -0,0 +1,43 @@
+// Server-Sent Events stream of file changes
+use std::time::Duration;
+use actix_web::{web, HttpResponse, Responder};
+use actix_web::web::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+use crate::server::events::EventBroadcaster;
+use crate::server::handlers::auth::auth_extractor::AuthUser;
+use crate::shared::models::{FileEvent, FileEventKind};
+
+// Comment lines sent while idle so proxies don't drop the connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+pub async fn events(auth: AuthUser, broadcaster: web::Data<EventBroadcaster>) -> impl Responder {
+    let username = auth.0.sub;
+    let receiver = broadcaster.subscribe();
+
+    let stream = futures_util::stream::unfold(receiver, move |mut receiver| {
+        let username = username.clone();
+        async move {
+            loop {
+                let message = match tokio::time::timeout(KEEP_ALIVE_INTERVAL, receiver.recv()).await {
+                    Ok(Ok((event_user, event))) if event_user == username => sse_message(&event),
+                    Ok(Ok(_)) => continue,
+                    // Events were dropped before this subscriber got to them
+                    Ok(Err(RecvError::Lagged(_))) => sse_message(&FileEvent::new(FileEventKind::Resync, String::new(), None)),
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Err(_) => String::from(": keep-alive\n\n"),
+                };
+
+                return Some((Ok::<Bytes, actix_web::Error>(Bytes::from(message)), receiver));
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
+fn sse_message(event: &FileEvent) -> String {
+    format!("data: {}\n\n", serde_json::to_string(event).unwrap_or_default())
+}
@@ -1,24 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{PathBuf, Component::ParentDir};
 use std::sync::Mutex;
 use actix_multipart::Multipart;
 // File upload and download handlers
-use actix_web::{web, Responder};
+use actix_web::{web, HttpResponse, Responder};
 use futures_util::{StreamExt, TryStreamExt};
 use rusqlite::Connection;
 use serde_json::json;
 use tokio::fs;
 use sanitize_filename;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use std::time::SystemTime;
 use chrono::{DateTime, Utc };
+use uuid::Uuid;
 use crate::shared::{
-    models::FileRequest,
+    models::{
+        ChangeList,
+        ChangesRequest,
+        ChunkCommitRequest,
+        ChunkInfo,
+        ChunkListRequest,
+        ChunkRequest,
+        FileEvent,
+        FileEventKind,
+        FileRequest,
+        FileRow,
+        MoveRequest,
+        SessionCreateRequest,
+        SessionData,
+        SessionRequest,
+        SessionRow,
+        TrashRequest,
+        VersionRequest
+    },
     utils
 };
-use crate::server::db;
+use crate::shared::errors::DbError;
+use crate::server::{blob_store, db};
+use crate::server::events::EventBroadcaster;
 use crate::server::handlers::auth::auth_extractor::AuthUser;
 
+// Most changes returned by one /file/changes request
+const CHANGES_PAGE_SIZE: usize = 1000;
+
 pub async fn files(auth: AuthUser, conn: web::Data<Mutex<Connection>>) -> impl Responder {
     let conn = conn.lock().unwrap();
     let user = auth.0;
@@ -78,12 +102,77 @@ pub async fn file(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Dat
 
 }
 
-pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mutex<Connection>>) -> impl Responder {
-    let conn = conn.lock().unwrap();
+pub async fn download(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let path = match query.path() {
+        Some(path) => path,
+        None => {
+            eprintln!("Path not in request");
+            return utils::bad_request_error(String::from("No path in request"));
+        }
+    };
+
+    let mut root_path = PathBuf::from(format!("uploads/{}", user.sub));
+    root_path.push(path);
+    let formatted_path = utils::format_file_path(&root_path.to_string_lossy().to_string());
+
+    let file_rows = {
+        let conn = conn.lock().unwrap();
+        match db::get_file(&conn, &formatted_path, &user.sub) {
+            Ok(file_rows) => file_rows,
+            Err(e) => {
+                eprintln!("Error fetching file");
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    let file_row = match file_rows.first() {
+        Some(file_row) => file_row.clone(),
+        None => return utils::not_found_error(String::from("File not found"))
+    };
+
+    let content_paths = {
+        let conn = conn.lock().unwrap();
+        match blob_store::content_paths(&conn, file_row.hash()) {
+            Ok(content_paths) => content_paths,
+            Err(e) => {
+                eprintln!("Error opening file: {:?}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    // Stream file contents back with the metadata the client needs to record it
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/octet-stream")
+        .insert_header((utils::FILE_HASH_HEADER, file_row.hash()))
+        .insert_header((utils::LAST_MODIFIED_HEADER, file_row.last_modified().to_rfc3339()));
+
+    if let Some(mode) = file_row.mode() {
+        response.insert_header((utils::FILE_MODE_HEADER, format!("{:o}", mode)));
+    }
+
+    if file_row.is_symlink() {
+        response.insert_header((utils::SYMLINK_HEADER, "true"));
+    }
+
+    response.streaming(blob_store::stream(content_paths))
+}
+
+pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
     let mut files_success: HashMap<String, String> = HashMap::new();
     let mut files_failure: HashMap<String, String> = HashMap::new();
     let mut last_modified_map: HashMap<String, DateTime<Utc>> = HashMap::new();
     let mut file_path_map: HashMap<String, String> = HashMap::new();
+    let mut base_hash_map: HashMap<String, String> = HashMap::new();
+    let mut hash_map: HashMap<String, String> = HashMap::new();
+    let mut mode_map: HashMap<String, u32> = HashMap::new();
+    let mut symlinks: HashSet<String> = HashSet::new();
+    let mut files_conflict: HashMap<String, FileRow> = HashMap::new();
     let username = auth.0.sub;
 
     // Iterate over the fields of the multipart file upload
@@ -132,6 +221,71 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
 
             continue;
 
+        } else if field_name.starts_with("base_hash_") {
+            // Hash of the file when the client last synced it, empty if it never has
+            let filename = field_name.strip_prefix("base_hash_").unwrap();
+            let mut data = Vec::new();
+
+            while let Some(chunk) = field.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+
+            let value_str = String::from_utf8_lossy(&data).trim().to_string();
+            base_hash_map.insert(filename.to_string(), value_str);
+
+            continue;
+
+        } else if field_name.starts_with("hash_") {
+            // Hash the client computed for the file, checked against what actually arrived
+            let filename = field_name.strip_prefix("hash_").unwrap();
+            let mut data = Vec::new();
+
+            while let Some(chunk) = field.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+
+            let value_str = String::from_utf8_lossy(&data).trim().to_string();
+            hash_map.insert(filename.to_string(), value_str);
+
+            continue;
+
+        } else if field_name.starts_with("mode_") {
+            // Permission bits in octal, only sent by clients on platforms that have them
+            let filename = field_name.strip_prefix("mode_").unwrap();
+            let mut data = Vec::new();
+
+            while let Some(chunk) = field.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+
+            let value_str = String::from_utf8_lossy(&data).trim().to_string();
+            match u32::from_str_radix(&value_str, 8) {
+                Ok(mode) => {
+                    mode_map.insert(filename.to_string(), mode);
+                }
+                Err(e) => {
+                    eprintln!("Failed to parse mode for {}: {:?}", filename, e);
+                    files_failure.insert(filename.to_string(), e.to_string());
+                }
+            }
+
+            continue;
+
+        } else if field_name.starts_with("symlink_") {
+            // The file's contents are the target of a symbolic link, to be recreated as one
+            let filename = field_name.strip_prefix("symlink_").unwrap();
+            let mut data = Vec::new();
+
+            while let Some(chunk) = field.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+
+            if String::from_utf8_lossy(&data).trim() == "true" {
+                symlinks.insert(filename.to_string());
+            }
+
+            continue;
+
         } else if field_name.starts_with("file_") {
             let filename = if let Some(name) = cd.get_filename() {
                 sanitize_filename::sanitize(name)
@@ -141,14 +295,6 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
             };
 
             let mut filepath = PathBuf::from(format!("uploads/{}", username));
-            match fs::create_dir_all(&filepath).await {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error with directory creation: {:?}", e);
-                    files_failure.insert(filename.clone(), e.to_string());
-                    continue;
-                }
-            }
 
             let sent_path_raw = file_path_map.get(&filename).unwrap();
             if !sent_path_raw.is_empty() {
@@ -159,18 +305,7 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
                     continue;
                 }
 
-                let final_path = filepath.join(&sent_path);
-
-                match fs::create_dir_all(&final_path).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Error with directory creation: {:?}", e);
-                        files_failure.insert(filename.clone(), e.to_string());
-                        continue;
-                    }
-                }
-
-                filepath = final_path.join(&filename);
+                filepath = filepath.join(&sent_path).join(&filename);
 
             } else {
                 filepath.push(&filename);
@@ -180,21 +315,9 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
 
             let file_query_path = utils::format_file_path(&filepath.to_string_lossy().to_string());
 
-            let file_rows = match db::get_file(&conn, &file_query_path, &username.to_string()) {
-                Ok(file_rows) => file_rows,
-                Err(e) => {
-                    eprintln!("Error with DB: {:?}", e);
-                    files_failure.insert(filename.clone(), e.to_string());
-                    continue;
-                }
-            };
-
-            if !file_rows.is_empty() {
-                files_failure.insert(filename.clone(), String::from("File already exists"));
-                continue;
-            }
-
-            let mut f = match fs::File::create(&filepath).await {
+            // Stage the upload, it only enters the blob store once fully written and hashed
+            let temp_path = blob_store::temp_path();
+            let mut f = match fs::File::create(&temp_path).await {
                 Ok(f) => f,
                 Err(e) => {
                     eprintln!("File creation error {:?}", e);
@@ -223,44 +346,109 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
             }
 
             if error_writing {
+                fs::remove_file(&temp_path).await.ok();
                 continue;
             }
 
-            if let Some(hash) = utils::hash_filepath(&filepath) {
-                let filtered_path = utils::format_file_path(&filepath.to_str().unwrap().to_string());
-                let last_modified = match last_modified_map.get(&filename) {
-                    Some(dt) => dt.clone(),
-                    None => DateTime::<Utc>::from(SystemTime::now())
-                };
-
-                let file_row = utils::convert_to_file_row(
-                    filtered_path,
-                    hash,
-                    last_modified,
-                );
-
-                match db::insert_file(&conn, &file_row, &username.to_string()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        files_failure.insert(filename.clone(), e.to_string());
-                        continue;
-                    }
+            if let Err(e) = f.sync_all().await {
+                eprintln!("Error syncing file: {:?}", e);
+                fs::remove_file(&temp_path).await.ok();
+                files_failure.insert(filename.clone(), e.to_string());
+                continue;
+            }
+
+            let hash = match utils::hash_filepath(&temp_path) {
+                Some(hash) => hash,
+                None => {
+                    fs::remove_file(&temp_path).await.ok();
+                    files_failure.insert(filename.clone(), String::from("Failed to hash file"));
+                    continue;
                 }
+            };
 
-                files_success.insert(filename.clone(), String::from("Success!"));
-            } else {
-                files_failure.insert(filename.clone(), String::from("Failed to hash file"));
+            // Whatever was corrupted in transit must not be stored as the file's contents
+            if let Some(expected_hash) = hash_map.get(&filename)
+                && *expected_hash != hash {
+                eprintln!("Hash mismatch for {}: expected {}, received {}", filename, expected_hash, hash);
+                fs::remove_file(&temp_path).await.ok();
+                files_failure.insert(filename.clone(), String::from("Hash mismatch: file was corrupted in transit"));
+                continue;
+            }
+
+            // Only hold the database while recording the file, so other uploads can stream in meanwhile
+            let conn = conn.lock().unwrap();
+
+            let file_rows = match db::get_file(&conn, &file_query_path, &username.to_string()) {
+                Ok(file_rows) => file_rows,
+                Err(e) => {
+                    eprintln!("Error with DB: {:?}", e);
+                    std::fs::remove_file(&temp_path).ok();
+                    files_failure.insert(filename.clone(), e.to_string());
+                    continue;
+                }
+            };
+
+            // Reject the upload if the server copy changed since the client last synced it
+            if let Some(existing) = file_rows.first()
+                && let Some(base_hash) = base_hash_map.get(&filename)
+                && conflicts_with(existing, base_hash, &hash) {
+                std::fs::remove_file(&temp_path).ok();
+                let mut existing = existing.clone();
+                strip_user_prefix(&mut existing, &username);
+                files_conflict.insert(filename.clone(), existing);
+                continue;
+            }
+
+            if let Err(e) = blob_store::ingest(&conn, &temp_path, &hash) {
+                eprintln!("Error storing file: {:?}", e);
+                std::fs::remove_file(&temp_path).ok();
+                files_failure.insert(filename.clone(), e.to_string());
+                continue;
+            }
+
+            let last_modified = match last_modified_map.get(&filename) {
+                Some(dt) => *dt,
+                None => DateTime::<Utc>::from(SystemTime::now())
+            };
+
+            let mut file_row = utils::convert_to_file_row(
+                file_query_path,
+                hash,
+                last_modified,
+            );
+            file_row.set_mode(mode_map.get(&filename).copied());
+            file_row.set_symlink(symlinks.contains(&filename));
+
+            match commit_change(&conn, &events, &username, |conn| commit_file(conn, file_rows.first(), &file_row, &username)) {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    files_failure.insert(filename.clone(), e.to_string());
+                    continue;
+                }
             }
 
+            files_success.insert(filename.clone(), String::from(if file_rows.is_empty() { "created" } else { "updated" }));
+
         } else {
             continue
         }
 
     }
 
-    if files_failure.is_empty() && files_success.is_empty() {
+    if files_failure.is_empty() && files_success.is_empty() && files_conflict.is_empty() {
         utils::bad_request_error(String::from("No files uploaded"))
+    } else if !files_conflict.is_empty() {
+        utils::conflict_error_data(
+            String::from("Files changed on the server since they were last synced"),
+            json!(
+                {
+                    "uploaded": files_success,
+                    "failed": files_failure,
+                    "conflicts": files_conflict,
+                }
+            )
+        )
     } else {
         utils::okay_response(
             Some(json!(
@@ -274,68 +462,1142 @@ pub async fn upload(auth: AuthUser, mut payload: Multipart, conn: web::Data<Mute
 
 }
 
-pub async fn delete(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+pub async fn missing_chunks(auth: AuthUser, body: web::Json<ChunkListRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
     let conn = conn.lock().unwrap();
+    let user = auth.0;
+
+    match db::get_missing_blobs(&conn, &body.hashes, &user.sub) {
+        Ok(missing) => utils::okay_response(Some(json!(missing))),
+        Err(e) => {
+            eprintln!("Error checking chunks: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+pub async fn upload_chunk(auth: AuthUser, query: web::Query<ChunkRequest>, mut payload: web::Payload, conn: web::Data<Mutex<Connection>>) -> impl Responder {
     let query = query.into_inner();
     let user = auth.0;
 
-    let path = match query.path() {
-        Some(path) => path,
+    let hash = match query.hash() {
+        Some(hash) => hash,
         None => {
-            eprintln!("No path found in request");
-            return utils::bad_request_error(String::from("No path found in request"));
+            eprintln!("Hash not in request");
+            return utils::bad_request_error(String::from("No hash in request"));
         }
     };
 
-    let mut root = PathBuf::from(format!("uploads/{}", user.sub));
-    root.push(path);
-    let filtered_path = utils::format_file_path(&root.to_string_lossy().to_string());
+    let temp_path = blob_store::temp_path();
+    let mut f = match fs::File::create(&temp_path).await {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("File creation error {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
 
-    let final_path = PathBuf::from(&filtered_path);
+    // Hash while writing, the chunk is only stored if it matches the hash it was sent under
+    let mut hasher = blake3::Hasher::new();
+    let mut size: u64 = 0;
 
-    if final_path.is_dir() {
-        // Remove dir (and recursively find files to delete within)
-        match fs::remove_dir_all(&final_path).await {
-            Ok(_) => {}
+    while let Some(data) = payload.next().await {
+        let data = match data {
+            Ok(data) => data,
             Err(e) => {
-                eprintln!("Error with directory creation: {:?}", e);
-                return utils::bad_request_error(String::from("Failed to delete directory"));
+                fs::remove_file(&temp_path).await.ok();
+                return utils::bad_request_error(e.to_string());
             }
+        };
+
+        size += data.len() as u64;
+        if size > utils::CHUNK_MAX_SIZE as u64 {
+            fs::remove_file(&temp_path).await.ok();
+            return utils::bad_request_error(String::from("Chunk is larger than the maximum chunk size"));
         }
 
-        utils::okay_response(None)
-    } else {
-        let file_row = match db::get_file(&conn, &filtered_path, &user.sub) {
-            Ok(file_row) => file_row,
+        hasher.update(&data);
+        if let Err(e) = f.write_all(&data).await {
+            eprintln!("Error with file: {:?}", e);
+            fs::remove_file(&temp_path).await.ok();
+            return utils::internal_server_error(e.to_string());
+        }
+    }
+
+    if let Err(e) = f.sync_all().await {
+        eprintln!("Error syncing file: {:?}", e);
+        fs::remove_file(&temp_path).await.ok();
+        return utils::internal_server_error(e.to_string());
+    }
+
+    if hasher.finalize().to_hex().as_str() != hash {
+        fs::remove_file(&temp_path).await.ok();
+        return utils::bad_request_error(String::from("Chunk contents do not match its hash"));
+    }
+
+    let conn = conn.lock().unwrap();
+    if let Err(e) = blob_store::ingest(&conn, &temp_path, hash) {
+        eprintln!("Error storing chunk: {:?}", e);
+        std::fs::remove_file(&temp_path).ok();
+        return utils::internal_server_error(e.to_string());
+    }
+
+    // Sending the contents is what lets this user use the chunk in their own files
+    match db::add_blob_owner(&conn, hash, &user.sub) {
+        Ok(_) => utils::okay_response(None),
+        Err(e) => {
+            eprintln!("Error storing chunk: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+pub async fn commit_chunks(auth: AuthUser, body: web::Json<ChunkCommitRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let request = body.into_inner();
+    let user = auth.0;
+
+    let sent_path = PathBuf::from(request.path());
+    if request.path().is_empty() || sent_path.is_absolute() || sent_path.components().any(|x| matches!(x, ParentDir)) {
+        return utils::bad_request_error(String::from("Invalid path: must be relative and not contain '..'"));
+    }
+
+    if request.chunks().is_empty() {
+        return utils::bad_request_error(String::from("No chunks in request"));
+    }
+
+    // Only chunks this user uploaded themselves can go into their file
+    let chunk_hashes = request.chunks().iter().map(|chunk| chunk.hash().to_string()).collect::<Vec<String>>();
+    let missing = {
+        let conn = conn.lock().unwrap();
+        match db::get_missing_blobs(&conn, &chunk_hashes, &user.sub) {
+            Ok(missing) => missing,
             Err(e) => {
-                eprintln!("Error fetching file row: {}", e.to_string());
+                eprintln!("Error checking chunks: {:?}", e);
                 return utils::internal_server_error(e.to_string());
             }
-        };
+        }
+    };
 
-        if file_row.is_empty() {
-            utils::not_found_error(String::from("File not found"))
-        } else {
-            // first delete file from system
-            match fs::remove_file(&filtered_path).await {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error deleting file: {:?}", e);
-                    return utils::internal_server_error(e.to_string());
-                }
-            }
+    if !missing.is_empty() {
+        return utils::bad_request_error(format!("{} chunks have not been uploaded", missing.len()));
+    }
 
-            // Then remove entry from db
-            match db::remove_file(&conn, &filtered_path, &user.sub) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error deleting file: {:?}", e);
-                    return utils::internal_server_error(e.to_string());
-                }
+    // The chunks have to add up to the file the manifest claims to describe
+    match hash_chunks(request.chunks()).await {
+        Ok(hash) if hash == request.hash() => {}
+        Ok(_) => return utils::bad_request_error(String::from("Chunks do not match the file hash")),
+        Err(e) => {
+            eprintln!("Error reading chunks: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    }
+
+    let conn = conn.lock().unwrap();
+    let formatted_path = user_file_path(&user.sub, request.path());
+
+    let file_rows = match db::get_file(&conn, &formatted_path, &user.sub) {
+        Ok(file_rows) => file_rows,
+        Err(e) => {
+            eprintln!("Error with DB: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // Same rule as multipart uploads, the server copy must not have moved on since the client last synced
+    if let Some(existing) = file_rows.first()
+        && conflicts_with(existing, request.base_hash(), request.hash()) {
+        let mut existing = existing.clone();
+        strip_user_prefix(&mut existing, &user.sub);
+        return utils::conflict_error_data(
+            String::from("File changed on the server since it was last synced"),
+            json!(existing)
+        );
+    }
+
+    let mut file_row = utils::convert_to_file_row(
+        formatted_path,
+        request.hash().to_string(),
+        request.last_modified(),
+    );
+    file_row.set_mode(request.mode());
+
+    // The manifest's chunk references and the file row go in together or not at all
+    let committed = commit_change(&conn, &events, &user.sub, |conn| {
+        db::insert_manifest(conn, request.hash(), request.chunks())?;
+        commit_file(conn, file_rows.first(), &file_row, &user.sub)
+    });
+
+    if let Err(e) = committed {
+        eprintln!("{:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    strip_user_prefix(&mut file_row, &user.sub);
+    utils::okay_response(Some(json!(file_row)))
+}
+
+pub async fn create_session(auth: AuthUser, body: web::Json<SessionCreateRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let request = body.into_inner();
+    let user = auth.0;
+
+    let sent_path = PathBuf::from(request.path());
+    if request.path().is_empty() || sent_path.is_absolute() || sent_path.components().any(|x| matches!(x, ParentDir)) {
+        return utils::bad_request_error(String::from("Invalid path: must be relative and not contain '..'"));
+    }
+
+    let formatted_path = user_file_path(&user.sub, request.path());
+
+    let sessions = match db::get_path_sessions(&conn, &formatted_path, &user.sub) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Error fetching sessions: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    for session in sessions {
+        // Same contents as before, so carry on from what was already received
+        if session.hash() == request.hash() && session.size() == request.size() {
+            if let Err(e) = db::touch_session(&conn, session.id()) {
+                eprintln!("Error updating session: {:?}", e);
+                return utils::internal_server_error(e.to_string());
             }
 
-            utils::okay_response(None)
+            return utils::okay_response(Some(json!(session_data(&session))));
+        }
+
+        // The file has changed since, the data received for it is no use
+        if let Err(e) = blob_store::remove_session(&conn, session.id()) {
+            eprintln!("Error removing session: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    }
+
+    let mut session = SessionRow::new(
+        Uuid::new_v4().to_string(),
+        formatted_path,
+        request.hash().to_string(),
+        request.size(),
+        request.last_modified(),
+        request.base_hash().to_string(),
+    );
+    session.set_mode(request.mode());
+
+    if let Err(e) = std::fs::File::create(blob_store::session_path(session.id())) {
+        eprintln!("File creation error {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    match db::insert_session(&conn, &session, &user.sub) {
+        Ok(_) => utils::okay_response(Some(json!(session_data(&session)))),
+        Err(e) => {
+            eprintln!("Error creating session: {:?}", e);
+            std::fs::remove_file(blob_store::session_path(session.id())).ok();
+            utils::internal_server_error(e.to_string())
         }
     }
+}
+
+pub async fn session_status(auth: AuthUser, query: web::Query<SessionRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let id = match query.id() {
+        Some(id) => id,
+        None => {
+            eprintln!("Session id not in request");
+            return utils::bad_request_error(String::from("No session id in request"));
+        }
+    };
+
+    match db::get_session(&conn, id, &user.sub) {
+        Ok(sessions) => match sessions.first() {
+            Some(session) => utils::okay_response(Some(json!(session_data(session)))),
+            None => utils::not_found_error(String::from("Session not found"))
+        },
+        Err(e) => {
+            eprintln!("Error fetching session: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+pub async fn upload_session_data(auth: AuthUser, query: web::Query<SessionRequest>, mut payload: web::Payload, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let (id, offset) = match (query.id(), query.offset()) {
+        (Some(id), Some(offset)) => (id, offset),
+        _ => {
+            eprintln!("Session id or offset not in request");
+            return utils::bad_request_error(String::from("Session id and offset are required"));
+        }
+    };
+
+    let sessions = {
+        let conn = conn.lock().unwrap();
+        match db::get_session(&conn, id, &user.sub) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                eprintln!("Error fetching session: {:?}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    let session = match sessions.first() {
+        Some(session) => session.clone(),
+        None => return utils::not_found_error(String::from("Session not found"))
+    };
+
+    // Data can only be appended at the end of what has been received, tell the client where that is
+    let received = session_data(&session);
+    if offset != received.offset {
+        return utils::conflict_error_data(String::from("Offset does not match the data received"), json!(received));
+    }
+
+    let mut f = match fs::OpenOptions::new().append(true).open(blob_store::session_path(session.id())).await {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error opening session data: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // Whatever arrives before the connection drops is kept, so the client can resume after it
+    let mut written = offset;
+    let mut error = None;
+
+    while let Some(data) = payload.next().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                error = Some(utils::bad_request_error(e.to_string()));
+                break;
+            }
+        };
+
+        if written + data.len() as u64 > session.size() {
+            error = Some(utils::bad_request_error(String::from("Data goes past the end of the file")));
+            break;
+        }
+
+        if let Err(e) = f.write_all(&data).await {
+            eprintln!("Error with file: {:?}", e);
+            error = Some(utils::internal_server_error(e.to_string()));
+            break;
+        }
+
+        written += data.len() as u64;
+    }
+
+    if let Err(e) = f.sync_all().await {
+        eprintln!("Error syncing file: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    {
+        let conn = conn.lock().unwrap();
+        if let Err(e) = db::touch_session(&conn, session.id()) {
+            eprintln!("Error updating session: {:?}", e);
+        }
+    }
+
+    match error {
+        Some(response) => response,
+        None => utils::okay_response(Some(json!(session_data(&session))))
+    }
+}
+
+pub async fn finalize_session(auth: AuthUser, query: web::Query<SessionRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let id = match query.id() {
+        Some(id) => id,
+        None => {
+            eprintln!("Session id not in request");
+            return utils::bad_request_error(String::from("No session id in request"));
+        }
+    };
+
+    let sessions = {
+        let conn = conn.lock().unwrap();
+        match db::get_session(&conn, id, &user.sub) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                eprintln!("Error fetching session: {:?}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    let session = match sessions.first() {
+        Some(session) => session.clone(),
+        None => return utils::not_found_error(String::from("Session not found"))
+    };
+
+    if session_data(&session).offset != session.size() {
+        return utils::bad_request_error(String::from("Upload is not complete"));
+    }
+
+    let session_path = blob_store::session_path(session.id());
+    let hash_matches = utils::hash_filepath(&session_path).as_deref() == Some(session.hash());
+
+    let conn = conn.lock().unwrap();
+
+    if !hash_matches {
+        if let Err(e) = blob_store::remove_session(&conn, session.id()) {
+            eprintln!("Error removing session: {:?}", e);
+        }
+        return utils::bad_request_error(String::from("Uploaded data does not match the expected hash"));
+    }
+
+    let file_rows = match db::get_file(&conn, &session.path().to_string(), &user.sub) {
+        Ok(file_rows) => file_rows,
+        Err(e) => {
+            eprintln!("Error with DB: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    if let Some(existing) = file_rows.first()
+        && conflicts_with(existing, session.base_hash(), session.hash()) {
+        if let Err(e) = blob_store::remove_session(&conn, session.id()) {
+            eprintln!("Error removing session: {:?}", e);
+        }
+        let mut existing = existing.clone();
+        strip_user_prefix(&mut existing, &user.sub);
+        return utils::conflict_error_data(
+            String::from("File changed on the server since it was last synced"),
+            json!(existing)
+        );
+    }
+
+    if let Err(e) = blob_store::ingest(&conn, &session_path, session.hash()) {
+        eprintln!("Error storing file: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    let mut file_row = utils::convert_to_file_row(
+        session.path().to_string(),
+        session.hash().to_string(),
+        session.last_modified(),
+    );
+    file_row.set_mode(session.mode());
+
+    if let Err(e) = commit_change(&conn, &events, &user.sub, |conn| commit_file(conn, file_rows.first(), &file_row, &user.sub)) {
+        eprintln!("{:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    if let Err(e) = db::remove_session(&conn, session.id()) {
+        eprintln!("Error removing session: {:?}", e);
+    }
+
+    strip_user_prefix(&mut file_row, &user.sub);
+    utils::okay_response(Some(json!(file_row)))
+}
+
+// Changes since a cursor, or just the latest cursor when none is given
+pub async fn changes(auth: AuthUser, query: web::Query<ChangesRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let user = auth.0;
+
+    let since = match query.since() {
+        Some(since) => since,
+        None => {
+            return match db::latest_change(&conn, &user.sub) {
+                Ok(cursor) => utils::okay_response(Some(json!(ChangeList { changes: Vec::new(), cursor, more: false }))),
+                Err(e) => {
+                    eprintln!("Error fetching changes: {:?}", e);
+                    utils::internal_server_error(e.to_string())
+                }
+            };
+        }
+    };
+
+    // Ask for one extra to tell whether another page follows
+    let mut changes = match db::get_changes(&conn, since, CHANGES_PAGE_SIZE + 1, &user.sub) {
+        Ok(changes) => changes,
+        Err(e) => {
+            eprintln!("Error fetching changes: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    let more = changes.len() > CHANGES_PAGE_SIZE;
+    changes.truncate(CHANGES_PAGE_SIZE);
+    let cursor = changes.last().map(|change| change.cursor()).unwrap_or(since);
+
+    utils::okay_response(Some(json!(ChangeList { changes, cursor, more })))
+}
+
+pub async fn versions(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let path = match query.path() {
+        Some(path) => path,
+        None => {
+            eprintln!("Path not in request");
+            return utils::bad_request_error(String::from("No path in request"));
+        }
+    };
+
+    let formatted_path = user_file_path(&user.sub, path);
+
+    match db::get_versions(&conn, &formatted_path, &user.sub) {
+        Ok(versions) => utils::okay_response(Some(json!(versions))),
+        Err(e) => {
+            eprintln!("Error fetching versions: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+pub async fn download_version(auth: AuthUser, query: web::Query<VersionRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let (path, version) = match (query.path(), query.version()) {
+        (Some(path), Some(version)) => (path, version),
+        _ => {
+            eprintln!("Path or version not in request");
+            return utils::bad_request_error(String::from("Path and version are required"));
+        }
+    };
+
+    let formatted_path = user_file_path(&user.sub, path);
+
+    let versions = {
+        let conn = conn.lock().unwrap();
+        match db::get_version(&conn, &formatted_path, version, &user.sub) {
+            Ok(versions) => versions,
+            Err(e) => {
+                eprintln!("Error fetching version: {:?}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    let file_version = match versions.first() {
+        Some(file_version) => file_version.clone(),
+        None => return utils::not_found_error(String::from("Version not found"))
+    };
+
+    let content_paths = {
+        let conn = conn.lock().unwrap();
+        match blob_store::content_paths(&conn, file_version.hash()) {
+            Ok(content_paths) => content_paths,
+            Err(e) => {
+                eprintln!("Error opening version: {:?}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((utils::FILE_HASH_HEADER, file_version.hash()))
+        .insert_header((utils::LAST_MODIFIED_HEADER, file_version.last_modified().to_rfc3339()))
+        .streaming(blob_store::stream(content_paths))
+}
+
+pub async fn restore_version(auth: AuthUser, query: web::Query<VersionRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let (path, version) = match (query.path(), query.version()) {
+        (Some(path), Some(version)) => (path, version),
+        _ => {
+            eprintln!("Path or version not in request");
+            return utils::bad_request_error(String::from("Path and version are required"));
+        }
+    };
+
+    let formatted_path = user_file_path(&user.sub, path);
+
+    let conn = conn.lock().unwrap();
+    let versions = match db::get_version(&conn, &formatted_path, version, &user.sub) {
+        Ok(versions) => versions,
+        Err(e) => {
+            eprintln!("Error fetching version: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    let file_version = match versions.first() {
+        Some(file_version) => file_version.clone(),
+        None => return utils::not_found_error(String::from("Version not found"))
+    };
+
+    let file_rows = match db::get_file(&conn, &formatted_path, &user.sub) {
+        Ok(file_rows) => file_rows,
+        Err(e) => {
+            eprintln!("Error fetching file: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // Restored content counts as a fresh change so clients pull it
+    let mut file_row = utils::convert_to_file_row(
+        formatted_path,
+        file_version.hash().to_string(),
+        Utc::now(),
+    );
+    // Versions archived before modes were kept fall back to the current file's
+    file_row.set_mode(file_version.mode().or_else(|| file_rows.first().and_then(|current| current.mode())));
+    file_row.set_symlink(file_version.is_symlink());
+
+    let result = commit_change(&conn, &events, &user.sub, |conn| {
+        // Archive the current contents too, so a restore can itself be undone
+        if let Some(current) = file_rows.first().filter(|current| current.hash() != file_version.hash()) {
+            archive_version(conn, current, &user.sub)?;
+        }
+
+        if file_rows.is_empty() {
+            db::insert_file(conn, &file_row, &user.sub)?;
+        } else {
+            db::update_file(conn, &file_row, &user.sub)?;
+        }
+
+        let kind = if file_rows.is_empty() { FileEventKind::Created } else { FileEventKind::Updated };
+        journal_change(conn, kind, &file_row, &user.sub).map(Some)
+    });
+
+    if let Err(e) = result {
+        eprintln!("Error restoring version: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    strip_user_prefix(&mut file_row, &user.sub);
+    utils::okay_response(Some(json!(file_row)))
+}
+
+pub async fn delete(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let path = match query.path() {
+        Some(path) => path,
+        None => {
+            eprintln!("No path found in request");
+            return utils::bad_request_error(String::from("No path found in request"));
+        }
+    };
+
+    // An empty path would name the user's whole tree
+    let sent_path = PathBuf::from(path);
+    if path.is_empty() || sent_path.is_absolute() || sent_path.components().any(|x| matches!(x, ParentDir)) {
+        return utils::bad_request_error(String::from("Invalid path: must be relative and not contain '..'"));
+    }
+
+    let filtered_path = user_file_path(&user.sub, path);
+
+    let file_rows = match db::get_file(&conn, &filtered_path, &user.sub) {
+        Ok(file_rows) => file_rows,
+        Err(e) => {
+            eprintln!("Error fetching file row: {}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // Contents live in the blob store, so a path with no row of its own is a directory
+    let (file_rows, directories) = if file_rows.is_empty() {
+        match db::get_files_under(&conn, &filtered_path, &user.sub)
+            .and_then(|file_rows| Ok((file_rows, db::get_directories_under(&conn, &filtered_path, &user.sub)?))) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Error fetching file rows: {}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    } else {
+        (file_rows, Vec::new())
+    };
+
+    if file_rows.is_empty() && directories.is_empty() {
+        return utils::not_found_error(String::from("File not found"));
+    }
+
+    // Delete everything or nothing
+    let transaction = match conn.unchecked_transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            eprintln!("Error starting transaction: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // Deleted files go to the trash, where they can be restored until they are purged
+    let mut deleted: Vec<FileEvent> = Vec::new();
+    for file_row in &file_rows {
+        let event = FileEvent::new(FileEventKind::Deleted, client_path(file_row.path(), &user.sub), None);
+        let result = db::trash_file(&transaction, file_row, &user.sub)
+            .and_then(|_| db::insert_change(&transaction, &event, &user.sub));
+
+        if let Err(e) = result {
+            eprintln!("Error deleting file: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+        deleted.push(event);
+    }
+
+    // Deepest first, so clients removing them locally find each one empty
+    for directory in &directories {
+        let event = FileEvent::new(FileEventKind::DirectoryDeleted, client_path(directory, &user.sub), None);
+        let result = db::remove_directory(&transaction, directory, &user.sub)
+            .and_then(|_| db::insert_change(&transaction, &event, &user.sub));
+
+        if let Err(e) = result {
+            eprintln!("Error deleting directory: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+        deleted.push(event);
+    }
+
+    if let Err(e) = transaction.commit() {
+        eprintln!("Error committing delete: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    let count = deleted.len();
+    for event in deleted {
+        events.publish(&user.sub, event);
+    }
+
+    utils::okay_response(Some(json!({ "deleted": count })))
+}
+
+pub async fn directories(auth: AuthUser, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let user = auth.0;
+
+    match db::get_directories(&conn, &user.sub) {
+        Ok(directories) => {
+            let directories = directories
+                .iter()
+                .map(|directory| client_path(directory, &user.sub))
+                .collect::<Vec<String>>();
+            utils::okay_response(Some(json!(directories)))
+        }
+        Err(e) => {
+            eprintln!("Error fetching directories: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+// Track a directory so it exists on every device even while it's empty
+pub async fn create_directory(auth: AuthUser, query: web::Query<FileRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let path = match query.path() {
+        Some(path) => path,
+        None => {
+            eprintln!("No path found in request");
+            return utils::bad_request_error(String::from("No path found in request"));
+        }
+    };
+
+    let sent_path = PathBuf::from(path);
+    if path.is_empty() || sent_path.is_absolute() || sent_path.components().any(|x| matches!(x, ParentDir)) {
+        return utils::bad_request_error(String::from("Invalid path: must be relative and not contain '..'"));
+    }
+
+    let formatted_path = user_file_path(&user.sub, path);
+
+    let conn = conn.lock().unwrap();
+    match db::get_file(&conn, &formatted_path, &user.sub) {
+        Ok(existing) if existing.is_empty() => {}
+        Ok(_) => return utils::conflict_error(format!("{} is a file", path)),
+        Err(e) => {
+            eprintln!("Error fetching file row: {}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    }
+
+    let event = FileEvent::new(FileEventKind::DirectoryCreated, client_path(&formatted_path, &user.sub), None);
+    let result = db::insert_directory(&conn, &formatted_path, &user.sub)
+        .and_then(|inserted| if inserted { db::insert_change(&conn, &event, &user.sub).map(|_| true) } else { Ok(false) });
+
+    match result {
+        Ok(true) => {
+            events.publish(&user.sub, event);
+            utils::okay_response(None)
+        }
+        Ok(false) => utils::okay_response(None),
+        Err(e) => {
+            eprintln!("Error creating directory: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+pub async fn trash(auth: AuthUser, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let user = auth.0;
+
+    match db::get_trash(&conn, &user.sub) {
+        Ok(mut trashed) => {
+            for trashed_file in trashed.iter_mut() {
+                trashed_file.set_path(client_path(trashed_file.path(), &user.sub));
+            }
+            utils::okay_response(Some(json!(trashed)))
+        }
+        Err(e) => {
+            eprintln!("Error fetching trash: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+// Put a trashed file back where it was deleted from
+pub async fn restore_trashed(auth: AuthUser, query: web::Query<TrashRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let id = match query.id() {
+        Some(id) => id,
+        None => {
+            eprintln!("Id not in request");
+            return utils::bad_request_error(String::from("No id in request"));
+        }
+    };
+
+    let conn = conn.lock().unwrap();
+    let trashed_file = match db::get_trashed(&conn, id, &user.sub) {
+        Ok(trashed) => match trashed.into_iter().next() {
+            Some(trashed_file) => trashed_file,
+            None => return utils::not_found_error(String::from("Trashed file not found")),
+        },
+        Err(e) => {
+            eprintln!("Error fetching trashed file: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    match db::get_file(&conn, &trashed_file.path().to_string(), &user.sub) {
+        Ok(existing) if existing.is_empty() => {}
+        Ok(_) => return utils::conflict_error(format!("{} already exists", client_path(trashed_file.path(), &user.sub))),
+        Err(e) => {
+            eprintln!("Error fetching file row: {}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    }
+
+    let mut file_row = utils::convert_to_file_row(
+        trashed_file.path().to_string(),
+        trashed_file.hash().to_string(),
+        trashed_file.last_modified()
+    );
+    file_row.set_mode(trashed_file.mode());
+    file_row.set_symlink(trashed_file.is_symlink());
+
+    let result = commit_change(&conn, &events, &user.sub, |conn| {
+        db::insert_file(conn, &file_row, &user.sub)?;
+        db::remove_trashed(conn, &[id])?;
+        journal_change(conn, FileEventKind::Created, &file_row, &user.sub).map(Some)
+    });
+
+    if let Err(e) = result {
+        eprintln!("Error restoring trashed file: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    strip_user_prefix(&mut file_row, &user.sub);
+    utils::okay_response(Some(json!(file_row)))
+}
+
+// Permanently remove one trashed file, or everything in the trash when no id is given
+pub async fn purge_trash(auth: AuthUser, query: web::Query<TrashRequest>, conn: web::Data<Mutex<Connection>>) -> impl Responder {
+    let conn = conn.lock().unwrap();
+    let query = query.into_inner();
+    let user = auth.0;
+
+    let ids = match query.id() {
+        Some(id) => match db::get_trashed(&conn, id, &user.sub) {
+            Ok(trashed) if trashed.is_empty() => return utils::not_found_error(String::from("Trashed file not found")),
+            Ok(_) => Ok(vec![id]),
+            Err(e) => Err(e),
+        },
+        None => db::get_trash_ids(&conn, &user.sub),
+    };
+
+    match ids.and_then(|ids| db::remove_trashed(&conn, &ids)) {
+        Ok(purged) => utils::okay_response(Some(json!({ "purged": purged }))),
+        Err(e) => {
+            eprintln!("Error purging trash: {:?}", e);
+            utils::internal_server_error(e.to_string())
+        }
+    }
+}
+
+// Rename a file or directory in place, keeping stored contents and history
+pub async fn move_file(auth: AuthUser, body: web::Json<MoveRequest>, conn: web::Data<Mutex<Connection>>, events: web::Data<EventBroadcaster>) -> impl Responder {
+    let request = body.into_inner();
+    let user = auth.0;
+
+    for path in [request.from(), request.to()] {
+        let sent_path = PathBuf::from(path);
+        if path.is_empty() || sent_path.is_absolute() || sent_path.components().any(|x| matches!(x, ParentDir)) {
+            return utils::bad_request_error(String::from("Invalid path: must be relative and not contain '..'"));
+        }
+    }
+
+    let from = user_file_path(&user.sub, request.from());
+    let to = user_file_path(&user.sub, request.to());
+
+    if from == to || to.starts_with(&format!("{}/", from)) {
+        return utils::bad_request_error(String::from("Cannot move a path into itself"));
+    }
+
+    let conn = conn.lock().unwrap();
+    let file_rows = match db::get_file(&conn, &from, &user.sub) {
+        Ok(file_rows) => file_rows,
+        Err(e) => {
+            eprintln!("Error fetching file row: {}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // A path with no row of its own is a directory, everything under it moves
+    let (moves, directory_moves) = if file_rows.is_empty() {
+        match db::get_files_under(&conn, &from, &user.sub)
+            .and_then(|file_rows| Ok((file_rows, db::get_directories_under(&conn, &from, &user.sub)?))) {
+            Ok((file_rows, directories)) => (
+                file_rows
+                    .into_iter()
+                    .map(|file_row| {
+                        let destination = format!("{}{}", to, &file_row.path()[from.len()..]);
+                        (file_row, destination)
+                    })
+                    .collect::<Vec<(FileRow, String)>>(),
+                directories
+                    .into_iter()
+                    .map(|directory| {
+                        let destination = format!("{}{}", to, &directory[from.len()..]);
+                        (directory, destination)
+                    })
+                    .collect::<Vec<(String, String)>>()
+            ),
+            Err(e) => {
+                eprintln!("Error fetching file rows: {}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    } else {
+        (file_rows.into_iter().map(|file_row| (file_row, to.clone())).collect(), Vec::new())
+    };
+
+    if moves.is_empty() && directory_moves.is_empty() {
+        return utils::not_found_error(String::from("File not found"));
+    }
+
+    let destinations = moves.iter().map(|(_, destination)| destination).chain(directory_moves.iter().map(|(_, destination)| destination));
+    for destination in destinations {
+        let existing = db::get_file(&conn, destination, &user.sub)
+            .and_then(|existing| Ok(!existing.is_empty() || db::directory_exists(&conn, destination, &user.sub)?));
+
+        match existing {
+            Ok(false) => {}
+            Ok(true) => return utils::conflict_error(format!("{} already exists", client_path(destination, &user.sub))),
+            Err(e) => {
+                eprintln!("Error fetching file row: {}", e);
+                return utils::internal_server_error(e.to_string());
+            }
+        }
+    }
+
+    // Move everything or nothing
+    let transaction = match conn.unchecked_transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            eprintln!("Error starting transaction: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+    };
+
+    // New directories go in shallowest first, ahead of the files moving into them
+    let mut moved: Vec<FileEvent> = Vec::new();
+    for (directory, destination) in directory_moves.iter().rev() {
+        let event = FileEvent::new(FileEventKind::DirectoryCreated, client_path(destination, &user.sub), None);
+        let result = db::move_directory(&transaction, directory, destination, &user.sub)
+            .and_then(|_| db::insert_change(&transaction, &event, &user.sub));
+
+        if let Err(e) = result {
+            eprintln!("Error moving directory: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+        moved.push(event);
+    }
+
+    for (file_row, destination) in &moves {
+        if let Err(e) = db::move_file(&transaction, &file_row.path().to_string(), destination, &user.sub) {
+            eprintln!("Error moving file: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+
+        let event = FileEvent::moved(
+            client_path(file_row.path(), &user.sub),
+            client_path(destination, &user.sub),
+            file_row.hash().to_string()
+        );
+        if let Err(e) = db::insert_change(&transaction, &event, &user.sub) {
+            eprintln!("Error recording change: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+        moved.push(event);
+    }
+
+    // The old directories are empty now, deepest first
+    for (directory, _) in &directory_moves {
+        let event = FileEvent::new(FileEventKind::DirectoryDeleted, client_path(directory, &user.sub), None);
+        if let Err(e) = db::insert_change(&transaction, &event, &user.sub) {
+            eprintln!("Error recording change: {:?}", e);
+            return utils::internal_server_error(e.to_string());
+        }
+        moved.push(event);
+    }
+
+    if let Err(e) = transaction.commit() {
+        eprintln!("Error committing move: {:?}", e);
+        return utils::internal_server_error(e.to_string());
+    }
+
+    for event in moved {
+        events.publish(&user.sub, event);
+    }
+
+    utils::okay_response(Some(json!({ "moved": moves.len() + directory_moves.len() })))
+}
+
+// Utility functions for file handlers
+
+// Server side path of a file within a user's uploads
+fn user_file_path(username: &str, path: &str) -> String {
+    let mut root_path = PathBuf::from(format!("uploads/{}", username));
+    root_path.push(path);
+    utils::format_file_path(&root_path.to_string_lossy().to_string())
+}
+
+// Path of a file as the client sees it
+fn strip_user_prefix(file: &mut FileRow, username: &str) {
+    file.set_path(client_path(file.path(), username));
+}
+
+fn client_path(path: &str, username: &str) -> String {
+    let stripped_path = PathBuf::from(path).strip_prefix(format!("uploads/{}/", username).as_str()).unwrap().to_path_buf();
+    stripped_path.to_string_lossy().to_string()
+}
+
+// An upload conflicts when the server copy moved on from the client's base to something else
+fn conflicts_with(existing: &FileRow, base_hash: &str, hash: &str) -> bool {
+    base_hash != existing.hash() && hash != existing.hash()
+}
+
+// How far an upload session has got, going by the data on disk
+fn session_data(session: &SessionRow) -> SessionData {
+    let offset = std::fs::metadata(blob_store::session_path(session.id()))
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+
+    SessionData {
+        id: session.id().to_string(),
+        offset,
+        size: session.size(),
+    }
+}
+
+// Run a change's writes in one transaction, so blob references never drift from the rows holding them,
+// and only tell subscribers once it's committed
+fn commit_change<F>(conn: &Connection, events: &EventBroadcaster, username: &str, write: F) -> Result<(), DbError>
+where
+    F: FnOnce(&Connection) -> Result<Option<FileEvent>, DbError>,
+{
+    let transaction = conn.unchecked_transaction()?;
+    let event = write(&transaction)?;
+    transaction.commit()?;
+
+    if let Some(event) = event {
+        events.publish(username, event);
+    }
+
+    Ok(())
+}
+
+// Point a file at newly stored contents, archiving whatever it held before. Returns the change for subscribers, if any
+fn commit_file(conn: &Connection, existing: Option<&FileRow>, file_row: &FileRow, username: &String) -> Result<Option<FileEvent>, DbError> {
+    match existing {
+        Some(existing) => {
+            // Clients without permission bits leave the ones the file already had
+            let mut file_row = file_row.clone();
+            if file_row.mode().is_none() {
+                file_row.set_mode(existing.mode());
+            }
+
+            if existing.hash() == file_row.hash() {
+                db::update_file(conn, &file_row, username)?;
+
+                // Only the permissions changed, other clients still need to hear about it
+                if existing.mode() != file_row.mode() {
+                    return journal_change(conn, FileEventKind::Updated, &file_row, username).map(Some);
+                }
+                return Ok(None);
+            }
+            archive_version(conn, existing, username)?;
+            db::update_file(conn, &file_row, username)?;
+            journal_change(conn, FileEventKind::Updated, &file_row, username).map(Some)
+        }
+        None => {
+            db::insert_file(conn, file_row, username)?;
+            journal_change(conn, FileEventKind::Created, file_row, username).map(Some)
+        }
+    }
+}
+
+// Journal a change to a server side file row under the path the client sees, returning the event to publish
+fn journal_change(conn: &Connection, kind: FileEventKind, file_row: &FileRow, username: &String) -> Result<FileEvent, DbError> {
+    let hash = match kind {
+        FileEventKind::Deleted => None,
+        _ => Some(file_row.hash().to_string()),
+    };
+
+    let mut event = FileEvent::new(kind, client_path(file_row.path(), username), hash);
+    if kind != FileEventKind::Deleted {
+        event.set_mode(file_row.mode());
+    }
+
+    db::insert_change(conn, &event, username)?;
+    Ok(event)
+}
+
+// Hash the concatenated contents of stored chunks
+async fn hash_chunks(chunks: &[ChunkInfo]) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    for chunk in chunks {
+        let mut f = fs::File::open(blob_store::blob_path(chunk.hash())).await?;
+        loop {
+            let bytes_read = f.read(&mut buf).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buf[..bytes_read]);
+        }
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
 
+// Keep the current contents of a file as a new version before they are replaced
+fn archive_version(conn: &Connection, current: &FileRow, username: &String) -> Result<(), DbError> {
+    let version = db::next_version(conn, &current.path().to_string(), username)?;
+    db::insert_version(conn, current, version, username)
 }
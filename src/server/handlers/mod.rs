@@ -1,3 +1,5 @@
 pub mod auth;
 
-pub mod file;
\ No newline at end of file
+pub mod file;
+
+pub mod events;
\ No newline at end of file
@@ -1,6 +1,8 @@
 pub mod server;
 pub mod handlers;
 mod db;
+mod blob_store;
+mod events;
 mod config_loader;
 
 pub use server::start;
\ No newline at end of file
@@ -1,36 +1,118 @@
 // Main logic for hosting Actix-Web HTTP server
 use actix_web::{web, App, HttpServer, Responder};
-use crate::server::handlers::{ file, auth };
-use crate::server::db;
+use crate::server::handlers::{ file, auth, events };
+use crate::server::{blob_store, db};
+use crate::server::events::EventBroadcaster;
 use std::sync::Mutex;
-use std::fs;
+use std::time::Duration;
 use crate::server::config_loader;
 use std::io;
+use chrono::{TimeDelta, Utc};
 use crate::shared::utils;
 
+const BLOB_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const JSON_LIMIT: usize = 32 * 1024 * 1024;
+
+// Deleted files are purged from the trash after TRASH_RETENTION_DAYS (from .env), 30 days by default
+const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
 pub async fn health() -> impl Responder {
     utils::okay_response(None)
 }
 
+fn trash_retention() -> TimeDelta {
+    let days = match std::env::var("TRASH_RETENTION_DAYS") {
+        Ok(days) => days.trim().parse::<i64>().unwrap_or_else(|e| {
+            eprintln!("Invalid TRASH_RETENTION_DAYS, using {} days: {}", DEFAULT_TRASH_RETENTION_DAYS, e);
+            DEFAULT_TRASH_RETENTION_DAYS
+        }),
+        Err(_) => DEFAULT_TRASH_RETENTION_DAYS,
+    };
+
+    TimeDelta::days(days)
+}
+
 // basic server health check route
 // main server startup
 pub async fn start(port: u16) -> io::Result<()> {
     if let Ok(db_conn) = db::init_db() {
-        let shared_conn = web::Data::new(Mutex::new(db_conn));
-        match fs::create_dir_all("uploads") {
+        match blob_store::init() {
             Ok(_) => (),
-            Err(e) => eprintln!("Error creating uploads dir: {:?}", e),
+            Err(e) => eprintln!("Error creating blob store dir: {:?}", e),
+        }
+
+        if let Err(e) = blob_store::migrate_legacy(&db_conn) {
+            eprintln!("Error moving existing uploads into the blob store: {}", e);
         }
+
+        let shared_conn = web::Data::new(Mutex::new(db_conn));
+
+        let trash_retention = trash_retention();
+
+        // Periodically purge expired trash, then delete blobs that nothing refers to any more, and abandoned upload sessions
+        let gc_conn = shared_conn.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(BLOB_GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                let conn = gc_conn.lock().unwrap();
+                let purged = db::get_expired_trash(&conn, Utc::now() - trash_retention)
+                    .and_then(|ids| db::remove_trashed(&conn, &ids));
+                match purged {
+                    Ok(0) => {}
+                    Ok(count) => println!("Purged {} files from the trash", count),
+                    Err(e) => eprintln!("Error purging trash: {}", e),
+                }
+
+                match blob_store::collect_garbage(&conn) {
+                    Ok(0) => {}
+                    Ok(count) => println!("Removed {} unreferenced blobs", count),
+                    Err(e) => eprintln!("Error collecting unreferenced blobs: {}", e),
+                }
+
+                match blob_store::expire_sessions(&conn) {
+                    Ok(0) => {}
+                    Ok(count) => println!("Removed {} expired upload sessions", count),
+                    Err(e) => eprintln!("Error expiring upload sessions: {}", e),
+                }
+            }
+        });
+
+        let broadcaster = web::Data::new(EventBroadcaster::default());
+
         let tls_config = config_loader::load_config();
         let server = HttpServer::new(move || {
             App::new()
                 .app_data(shared_conn.clone())
+                .app_data(broadcaster.clone())
+                // Chunk manifests for large files are well over the default JSON limit
+                .app_data(web::JsonConfig::default().limit(JSON_LIMIT))
                 .route("/health", web::get().to(health))
 
                 .route("/file/list", web::get().to(file::files))
+                .route("/file/changes", web::get().to(file::changes))
                 .route("/file/metadata", web::get().to(file::file))
+                .route("/file/download", web::get().to(file::download))
                 .route("/file/upload", web::post().to(file::upload))
+                .route("/file/chunks/missing", web::post().to(file::missing_chunks))
+                .route("/file/chunk", web::put().to(file::upload_chunk))
+                .route("/file/chunks/commit", web::post().to(file::commit_chunks))
+                .route("/file/session", web::post().to(file::create_session))
+                .route("/file/session", web::get().to(file::session_status))
+                .route("/file/session", web::put().to(file::upload_session_data))
+                .route("/file/session/finalize", web::post().to(file::finalize_session))
                 .route("/file/delete", web::delete().to(file::delete))
+                .route("/file/move", web::post().to(file::move_file))
+                .route("/file/directories", web::get().to(file::directories))
+                .route("/file/directory", web::post().to(file::create_directory))
+                .route("/file/versions", web::get().to(file::versions))
+                .route("/file/version/download", web::get().to(file::download_version))
+                .route("/file/version/restore", web::post().to(file::restore_version))
+                .route("/file/trash", web::get().to(file::trash))
+                .route("/file/trash/restore", web::post().to(file::restore_trashed))
+                .route("/file/trash/purge", web::delete().to(file::purge_trash))
+
+                .route("/events", web::get().to(events::events))
 
                 .route("/auth/register", web::post().to(auth::register))
                 .route("/auth/login", web::post().to(auth::login))
@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
 // Shared file for data type models
@@ -7,6 +9,21 @@ pub struct FileRow {
     path: String,
     hash: String,
     last_modified: DateTime<Utc>,
+    // Last hash the server acknowledged for this file (client side only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_hash: Option<String>,
+    // POSIX permission bits, unknown for files from platforms without them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    // A symbolic link synced as a link, its contents are the link's target
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    symlink: bool,
+    // Size on disk when the file was last hashed (client side only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    // Inode the file was last hashed at, on platforms that have them (client side only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
 }
 
 impl FileRow {
@@ -15,6 +32,11 @@ impl FileRow {
             path,
             hash,
             last_modified,
+            base_hash: None,
+            mode: None,
+            symlink: false,
+            size: None,
+            inode: None,
         }
     }
 
@@ -30,6 +52,26 @@ impl FileRow {
         self.last_modified
     }
 
+    pub fn base_hash(&self) -> Option<&str> {
+        self.base_hash.as_deref()
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn inode(&self) -> Option<u64> {
+        self.inode
+    }
+
     pub fn set_hash(&mut self, hash: String) {
         self.hash = hash;
     }
@@ -41,8 +83,388 @@ impl FileRow {
     pub fn set_path(&mut self, path: String) {
         self.path = path;
     }
+
+    pub fn set_base_hash(&mut self, base_hash: Option<String>) {
+        self.base_hash = base_hash;
+    }
+
+    pub fn set_mode(&mut self, mode: Option<u32>) {
+        self.mode = mode;
+    }
+
+    pub fn set_symlink(&mut self, symlink: bool) {
+        self.symlink = symlink;
+    }
+
+    pub fn set_size(&mut self, size: Option<u64>) {
+        self.size = size;
+    }
+
+    pub fn set_inode(&mut self, inode: Option<u64>) {
+        self.inode = inode;
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileVersion {
+    version: i64,
+    hash: String,
+    last_modified: DateTime<Utc>,
+    archived_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    symlink: bool,
+}
+
+impl FileVersion {
+    pub fn new(version: i64, hash: String, last_modified: DateTime<Utc>, archived_at: DateTime<Utc>) -> Self {
+        FileVersion {
+            version,
+            hash,
+            last_modified,
+            archived_at,
+            mode: None,
+            symlink: false,
+        }
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Option<u32>) {
+        self.mode = mode;
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    pub fn set_symlink(&mut self, symlink: bool) {
+        self.symlink = symlink;
+    }
+
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn archived_at(&self) -> DateTime<Utc> {
+        self.archived_at
+    }
+}
+
+// A deleted file held on the server until it is restored or purged
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrashedFile {
+    id: i64,
+    path: String,
+    hash: String,
+    last_modified: DateTime<Utc>,
+    deleted_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    symlink: bool,
+}
+
+impl TrashedFile {
+    pub fn new(id: i64, path: String, hash: String, last_modified: DateTime<Utc>, deleted_at: DateTime<Utc>) -> Self {
+        TrashedFile {
+            id,
+            path,
+            hash,
+            last_modified,
+            deleted_at,
+            mode: None,
+            symlink: false,
+        }
+    }
+
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn set_path(&mut self, path: String) {
+        self.path = path;
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn deleted_at(&self) -> DateTime<Utc> {
+        self.deleted_at
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Option<u32>) {
+        self.mode = mode;
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    pub fn set_symlink(&mut self, symlink: bool) {
+        self.symlink = symlink;
+    }
+}
+
+// One content defined piece of a file, addressed by its BLAKE3 hash
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    hash: String,
+    size: u64,
+}
+
+impl ChunkInfo {
+    pub fn new(hash: String, size: u64) -> Self {
+        ChunkInfo { hash, size }
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+// A change the client still has to get the server to acknowledge
+#[derive(Clone, Debug)]
+pub struct PendingOp {
+    path: String,
+    kind: OpKind,
+    attempts: u32,
+}
+
+impl PendingOp {
+    pub fn new(path: String, kind: OpKind, attempts: u32) -> Self {
+        PendingOp { path, kind, attempts }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn kind(&self) -> OpKind {
+        self.kind
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpKind {
+    Upload,
+    Delete,
+    CreateDirectory,
+}
+
+impl OpKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OpKind::Upload => "upload",
+            OpKind::Delete => "delete",
+            OpKind::CreateDirectory => "create-directory",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "upload" => Some(OpKind::Upload),
+            "delete" => Some(OpKind::Delete),
+            "create-directory" => Some(OpKind::CreateDirectory),
+            _ => None,
+        }
+    }
+}
+
+// Whether a remote subtree is kept locally, the most specific rule covering a path wins
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectiveRule {
+    path: String,
+    mode: SelectiveMode,
+}
+
+impl SelectiveRule {
+    pub fn new(path: String, mode: SelectiveMode) -> Self {
+        SelectiveRule { path, mode }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn mode(&self) -> SelectiveMode {
+        self.mode
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectiveMode {
+    Include,
+    Exclude,
+}
+
+impl SelectiveMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SelectiveMode::Include => "include",
+            SelectiveMode::Exclude => "exclude",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "include" => Some(SelectiveMode::Include),
+            "exclude" => Some(SelectiveMode::Exclude),
+            _ => None,
+        }
+    }
+}
+
+// A change to a user's files, pushed to their other devices over /events
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileEvent {
+    kind: FileEventKind,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    // Where a moved file used to be
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    // Permission bits the file now has, so a change to them alone still reaches other devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+}
+
+impl FileEvent {
+    pub fn new(kind: FileEventKind, path: String, hash: Option<String>) -> Self {
+        FileEvent { kind, path, hash, from: None, mode: None }
+    }
+
+    pub fn moved(from: String, path: String, hash: String) -> Self {
+        FileEvent { kind: FileEventKind::Moved, path, hash: Some(hash), from: Some(from), mode: None }
+    }
+
+    pub fn from(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn kind(&self) -> FileEventKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Option<u32>) {
+        self.mode = mode;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileEventKind {
+    Created,
+    Updated,
+    Deleted,
+    Moved,
+    DirectoryCreated,
+    DirectoryDeleted,
+    // The subscriber may have missed events and should catch up from the change journal
+    Resync,
+}
+
+impl FileEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileEventKind::Created => "created",
+            FileEventKind::Updated => "updated",
+            FileEventKind::Deleted => "deleted",
+            FileEventKind::Moved => "moved",
+            FileEventKind::DirectoryCreated => "directory-created",
+            FileEventKind::DirectoryDeleted => "directory-deleted",
+            FileEventKind::Resync => "resync",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "created" => Some(FileEventKind::Created),
+            "updated" => Some(FileEventKind::Updated),
+            "deleted" => Some(FileEventKind::Deleted),
+            "moved" => Some(FileEventKind::Moved),
+            "directory-created" => Some(FileEventKind::DirectoryCreated),
+            "directory-deleted" => Some(FileEventKind::DirectoryDeleted),
+            "resync" => Some(FileEventKind::Resync),
+            _ => None,
+        }
+    }
+}
+
+// An entry in the server's change journal, cursors increase with every change recorded
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileChange {
+    cursor: i64,
+    #[serde(flatten)]
+    event: FileEvent,
 }
 
+impl FileChange {
+    pub fn new(cursor: i64, event: FileEvent) -> Self {
+        FileChange { cursor, event }
+    }
+
+    pub fn cursor(&self) -> i64 {
+        self.cursor
+    }
+
+    pub fn event(&self) -> &FileEvent {
+        &self.event
+    }
+}
+
+// A page of changes, cursor is where the next request should continue from
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangeList {
+    pub changes: Vec<FileChange>,
+    pub cursor: i64,
+    pub more: bool,
+}
 
 #[derive(Debug, Deserialize)]
 pub struct FileRequest {
@@ -55,6 +477,261 @@ impl FileRequest {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MoveRequest {
+    from: String,
+    to: String,
+}
+
+impl MoveRequest {
+    pub fn new(from: String, to: String) -> Self {
+        MoveRequest { from, to }
+    }
+
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionRequest {
+    path: Option<String>,
+    version: Option<i64>,
+}
+
+impl VersionRequest {
+    pub fn path(&self) -> &Option<String> {
+        &self.path
+    }
+
+    pub fn version(&self) -> Option<i64> {
+        self.version
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrashRequest {
+    id: Option<i64>,
+}
+
+impl TrashRequest {
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesRequest {
+    since: Option<i64>,
+}
+
+impl ChangesRequest {
+    pub fn since(&self) -> Option<i64> {
+        self.since
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkRequest {
+    hash: Option<String>,
+}
+
+impl ChunkRequest {
+    pub fn hash(&self) -> &Option<String> {
+        &self.hash
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkListRequest {
+    pub hashes: Vec<String>,
+}
+
+// Manifest of already uploaded chunks that make up a file, in order
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkCommitRequest {
+    path: String,
+    hash: String,
+    last_modified: DateTime<Utc>,
+    #[serde(default)]
+    base_hash: String,
+    #[serde(default)]
+    mode: Option<u32>,
+    chunks: Vec<ChunkInfo>,
+}
+
+impl ChunkCommitRequest {
+    pub fn new(file: &FileRow, hash: String, chunks: Vec<ChunkInfo>) -> Self {
+        ChunkCommitRequest {
+            path: file.path().to_string(),
+            hash,
+            last_modified: file.last_modified(),
+            base_hash: file.base_hash().unwrap_or("").to_string(),
+            mode: file.mode(),
+            chunks,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn base_hash(&self) -> &str {
+        &self.base_hash
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn chunks(&self) -> &[ChunkInfo] {
+        &self.chunks
+    }
+}
+
+// Opens an upload session, or resumes the one already open for this path and contents
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionCreateRequest {
+    path: String,
+    hash: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    #[serde(default)]
+    base_hash: String,
+    #[serde(default)]
+    mode: Option<u32>,
+}
+
+impl SessionCreateRequest {
+    pub fn new(file: &FileRow, hash: String, size: u64) -> Self {
+        SessionCreateRequest {
+            path: file.path().to_string(),
+            hash,
+            size,
+            last_modified: file.last_modified(),
+            base_hash: file.base_hash().unwrap_or("").to_string(),
+            mode: file.mode(),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn base_hash(&self) -> &str {
+        &self.base_hash
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionRequest {
+    id: Option<String>,
+    offset: Option<u64>,
+}
+
+impl SessionRequest {
+    pub fn id(&self) -> &Option<String> {
+        &self.id
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+}
+
+// Server side record of an upload session, its data is the bytes received so far
+#[derive(Clone, Debug)]
+pub struct SessionRow {
+    id: String,
+    path: String,
+    hash: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    base_hash: String,
+    mode: Option<u32>,
+}
+
+impl SessionRow {
+    pub fn new(id: String, path: String, hash: String, size: u64, last_modified: DateTime<Utc>, base_hash: String) -> Self {
+        SessionRow {
+            id,
+            path,
+            hash,
+            size,
+            last_modified,
+            base_hash,
+            mode: None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn base_hash(&self) -> &str {
+        &self.base_hash
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Option<u32>) {
+        self.mode = mode;
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionData {
+    pub id: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: String,
@@ -176,8 +853,118 @@ pub struct RefreshData {
 }
 
 #[derive(Debug, Deserialize)]
+pub struct FileListResponse {
+    pub data: Vec<FileRow>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileResponse {
+    pub data: FileRow,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionListResponse {
+    pub data: Vec<FileVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DirectoryListResponse {
+    pub data: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrashListResponse {
+    pub data: Vec<TrashedFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeResponse {
+    pub data: PurgeData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeData {
+    pub purged: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesResponse {
+    pub data: ChangeList,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkListResponse {
+    pub data: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionResponse {
+    pub data: SessionData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadResponse {
+    pub data: UploadData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadData {
+    pub uploaded: HashMap<String, String>,
+    pub failed: HashMap<String, String>,
+    #[serde(default)]
+    pub conflicts: HashMap<String, FileRow>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub url: String,
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    // Upload requests the client keeps in flight at once
+    #[serde(default = "default_upload_concurrency")]
+    pub upload_concurrency: usize,
+    #[serde(default)]
+    pub symlink_policy: SymlinkPolicy,
+    // Seconds between full rescans of the watched directory while watching, 0 turns them off
+    #[serde(default = "default_rescan_interval")]
+    pub rescan_interval: u64,
+    // Whether preserved links may point outside the watched directory once recreated here
+    #[serde(default)]
+    pub allow_external_links: bool,
+}
+
+fn default_upload_concurrency() -> usize {
+    4
+}
+
+fn default_rescan_interval() -> u64 {
+    300
+}
+
+// How the client settles a file changed both locally and on the server
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // Keep the server copy and save the local one alongside it as a conflict copy
+    #[default]
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+    // Whichever side has the later last_modified wins
+    PreferNewest,
+}
+
+// What the client does with symbolic links in the watched directory
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    // Leave links out of syncing altogether
+    Skip,
+    // Sync whatever a link points at, as long as it is inside the watched directory
+    #[default]
+    FollowWithinRoot,
+    // Sync the link itself, recreating it with the same target on other devices
+    Preserve,
 }
 
 
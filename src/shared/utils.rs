@@ -1,9 +1,9 @@
 // Helper utils for file syncing
 
-use std::path::{ PathBuf };
+use std::path::{ Component, Path, PathBuf };
 use std::fs::File;
 use std::error::Error;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read};
 use actix_web::{HttpRequest, HttpResponse};
 use actix_web::http::header::Header;
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
@@ -12,12 +12,34 @@ use argon2::PasswordVerifier;
 use blake3;
 use chrono::{DateTime, Utc};
 use directories_next::ProjectDirs;
+use fastcdc::v2020::StreamCDC;
 use jsonwebtoken::{DecodingKey, Validation};
 use serde_json::json;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use crate::shared::errors::AuthError;
-use crate::shared::models::{AuthRequest, Config, FileRow, LoginTokenData, UserAccessToken};
+use crate::shared::models::{AuthRequest, ChunkInfo, Config, FileRow, LoginTokenData, UserAccessToken};
+
+// Response headers carrying file metadata on downloads
+pub const FILE_HASH_HEADER: &str = "X-File-Hash";
+pub const LAST_MODIFIED_HEADER: &str = "X-Last-Modified";
+pub const FILE_MODE_HEADER: &str = "X-File-Mode";
+// Set when the download is a symbolic link, its contents being the link's target
+pub const SYMLINK_HEADER: &str = "X-Symlink";
+
+// Content defined chunk size bounds, files at least CHUNKED_UPLOAD_THRESHOLD long are uploaded in chunks
+pub const CHUNK_MIN_SIZE: u32 = 256 * 1024;
+pub const CHUNK_AVG_SIZE: u32 = 1024 * 1024;
+pub const CHUNK_MAX_SIZE: u32 = 4 * 1024 * 1024;
+pub const CHUNKED_UPLOAD_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+// Files at least this long go through a resumable upload session, sent in parts of SESSION_PART_SIZE
+pub const RESUMABLE_UPLOAD_THRESHOLD: u64 = 1024 * 1024;
+pub const SESSION_PART_SIZE: u64 = 1024 * 1024;
+
+// Files are hashed a block of this size at a time, large enough for blake3 to spread each block across threads.
+// Reading rather than memory mapping keeps a file truncated mid-hash from taking the client down
+const HASH_BLOCK_SIZE: usize = 1024 * 1024;
 
 // Check if file path is valid
 pub fn check_file_path(path: &PathBuf) -> bool {
@@ -35,14 +57,13 @@ pub fn check_file_path(path: &PathBuf) -> bool {
     true
 }
 
-pub fn hash_file(file: &File) -> Option<String> {
-    let mut reader = BufReader::new(file);
+pub fn hash_file(mut file: &File) -> Option<String> {
     let mut hasher = blake3::Hasher::new();
 
-    let mut buf = [0u8; 8192];
+    let mut buf = vec![0u8; HASH_BLOCK_SIZE];
 
     loop {
-        let bytes_read = match reader.read(&mut buf) {
+        let bytes_read = match file.read(&mut buf) {
             Ok(n) => n,
             Err(e) => {
                 eprintln!("Read error: {:?}", e);
@@ -53,7 +74,7 @@ pub fn hash_file(file: &File) -> Option<String> {
             break;
         }
 
-        hasher.update(&buf[..bytes_read]);
+        hasher.update_rayon(&buf[..bytes_read]);
     }
 
     Some(hasher.finalize().to_hex().to_string())
@@ -84,10 +105,146 @@ pub fn hash_filepath(filepath: &PathBuf) -> Option<String> {
     Some(hasher.finalize().to_hex().to_string())
 }
 
+// The target of a symbolic link, which is what gets uploaded and hashed for a link synced as a link
+pub fn link_target_bytes(path: &Path) -> io::Result<Vec<u8>> {
+    let target = std::fs::read_link(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(target.as_os_str().as_bytes().to_vec())
+    }
+
+    #[cfg(not(unix))]
+    Ok(target.to_string_lossy().as_bytes().to_vec())
+}
+
+pub fn hash_link(path: &Path) -> Option<String> {
+    let target = link_target_bytes(path).ok()?;
+    Some(blake3::hash(&target).to_hex().to_string())
+}
+
+// Point a new symbolic link at target. Platforms without them get a file holding the target instead
+#[cfg(unix)]
+pub fn create_symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+pub fn create_symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    std::fs::write(path, target)
+}
+
+// Where a link at path pointing at target ends up, without following any links along the way
+pub fn resolve_link_target(path: &Path, target: &[u8]) -> PathBuf {
+    let target = PathBuf::from(String::from_utf8_lossy(target).to_string());
+    let joined = match path.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    };
+
+    let mut resolved = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => resolved.push(other),
+        }
+    }
+
+    resolved
+}
+
+// Split a file into content defined chunks, returning the whole file hash and each chunk's offset and hash
+pub fn chunk_filepath(filepath: &Path) -> io::Result<(String, Vec<(u64, ChunkInfo)>)> {
+    let file = File::open(filepath)?;
+    let chunker = StreamCDC::new(BufReader::new(file), CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE);
+    let mut hasher = blake3::Hasher::new();
+    let mut chunks = Vec::new();
+
+    for chunk in chunker {
+        let chunk = chunk?;
+        hasher.update(&chunk.data);
+        chunks.push((
+            chunk.offset,
+            ChunkInfo::new(blake3::hash(&chunk.data).to_hex().to_string(), chunk.length as u64)
+        ));
+    }
+
+    Ok((hasher.finalize().to_hex().to_string(), chunks))
+}
+
+// Permission bits of a file, on platforms that have them
+#[cfg(unix)]
+pub fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Inode number of a file, on platforms that have them
+#[cfg(unix)]
+pub fn file_inode(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+pub fn file_inode(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+// Give a synced file the modified time and permissions it had on the device that uploaded it
+pub fn apply_file_metadata(path: &Path, mode: Option<u32>, last_modified: DateTime<Utc>) -> io::Result<()> {
+    // Times first, the new permissions might not allow opening the file for writing
+    File::options().write(true).open(path)?.set_modified(last_modified.into())?;
+
+    match mode {
+        Some(mode) => set_file_mode(path, mode),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
 pub fn format_file_path(path: &String) -> String {
     path.replace("\\", "/").replace("./", "")
 }
 
+// Temp file alongside path, named so the watcher ignores it
+pub fn temp_file_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("file");
+    path.with_file_name(format!(".{}.rustysync.tmp", file_name))
+}
+
+// Strip the watched directory prefix from a synced path, giving the path relative to the watch root
+pub fn relative_to_root(path: &str, init_dir: &Path) -> Option<String> {
+    let prefix = format_file_path(&init_dir.to_string_lossy().to_string());
+    let prefix = prefix.trim_end_matches('/');
+
+    if prefix.is_empty() || prefix == "." {
+        return Some(path.to_string());
+    }
+
+    path.strip_prefix(prefix)?.strip_prefix("/").map(|p| p.to_string())
+}
+
 pub fn convert_to_file_row(path: String, hash: String, last_modified: DateTime<Utc>) -> FileRow {
     FileRow::new(
         path,
@@ -96,6 +253,21 @@ pub fn convert_to_file_row(path: String, hash: String, last_modified: DateTime<U
     )
 }
 
+// Add a column to an existing table, returning whether it had to be added
+pub fn ensure_column(conn: &rusqlite::Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<bool> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    if columns.iter().any(|c| c == column) {
+        return Ok(false);
+    }
+
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    Ok(true)
+}
+
 pub fn internal_server_error(error: String) -> HttpResponse {
     HttpResponse::InternalServerError().json(json!({ "status": "INTERNAL_SERVER_ERROR", "error": error }))
 }
@@ -119,6 +291,10 @@ pub fn conflict_error(error: String) -> HttpResponse {
     HttpResponse::Conflict().json(json!({ "status": "CONFLICT", "error": error }))
 }
 
+pub fn conflict_error_data(error: String, data: serde_json::Value) -> HttpResponse {
+    HttpResponse::Conflict().json(json!({ "status": "CONFLICT", "error": error, "data": data }))
+}
+
 pub fn authorization_error(error: String) -> HttpResponse {
     HttpResponse::Unauthorized().json(json!({ "status": "UNAUTHORIZED", "error": error }))
 }
@@ -177,6 +353,12 @@ pub fn config_file_error() -> String {
 }
 
 pub async fn load_url() -> Result<String, Box<dyn Error>> {
+    let config = load_config().await?;
+
+    Ok(config.url)
+}
+
+pub async fn load_config() -> Result<Config, Box<dyn Error>> {
     // Get config dir
     let config_dir = match get_config_path().await {
         Some(path) => path,
@@ -199,7 +381,27 @@ pub async fn load_url() -> Result<String, Box<dyn Error>> {
     config_file.read_to_string(&mut config_string).await?;
     let config: Config = serde_json::from_str(&config_string)?;
 
-    Ok(config.url)
+    Ok(config)
+}
+
+// Name this device goes by in conflict copies
+pub fn device_name() -> String {
+    hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(String::from("unknown device"))
+}
+
+// Sibling path for a conflicting local copy, e.g. "notes (conflict from laptop 2025-01-31 10-15-00).txt"
+pub fn conflict_file_path(path: &Path, device: &str, at: DateTime<Utc>) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
+    let suffix = format!(" (conflict from {} {})", device, at.format("%Y-%m-%d %H-%M-%S"));
+
+    let file_name = match path.extension().and_then(|s| s.to_str()) {
+        Some(extension) => format!("{}{}.{}", stem, suffix, extension),
+        None => format!("{}{}", stem, suffix),
+    };
+
+    path.with_file_name(file_name)
 }
 
 pub async fn load_access_token() -> Result<(String, usize), Box<dyn Error>> {
@@ -240,3 +442,46 @@ pub async fn get_config_path() -> Option<PathBuf> {
 
     Some(config_dir.to_path_buf())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn conflict_name(path: &str) -> String {
+        let at = Utc.with_ymd_and_hms(2025, 1, 31, 10, 15, 0).unwrap();
+        conflict_file_path(Path::new(path), "laptop", at).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn conflict_path_keeps_extension_last() {
+        assert_eq!(conflict_name("docs/notes.txt"), "docs/notes (conflict from laptop 2025-01-31 10-15-00).txt");
+    }
+
+    #[test]
+    fn conflict_path_without_extension() {
+        assert_eq!(conflict_name("docs/Makefile"), "docs/Makefile (conflict from laptop 2025-01-31 10-15-00)");
+    }
+
+    #[test]
+    fn conflict_path_for_dotfile() {
+        // A leading dot marks a hidden file, not an extension
+        assert_eq!(conflict_name("home/.bashrc"), "home/.bashrc (conflict from laptop 2025-01-31 10-15-00)");
+    }
+
+    #[test]
+    fn conflict_path_with_several_dots() {
+        // Only the last extension is kept after the suffix
+        assert_eq!(conflict_name("backup.tar.gz"), "backup.tar (conflict from laptop 2025-01-31 10-15-00).gz");
+    }
+
+    #[test]
+    fn link_target_resolves_against_link_directory() {
+        let link = Path::new("/sync/docs/link");
+
+        assert_eq!(resolve_link_target(link, b"notes.txt"), Path::new("/sync/docs/notes.txt"));
+        assert_eq!(resolve_link_target(link, b"../other/./a.txt"), Path::new("/sync/other/a.txt"));
+        assert_eq!(resolve_link_target(link, b"../../../etc/passwd"), Path::new("/etc/passwd"));
+        assert_eq!(resolve_link_target(link, b"/etc/passwd"), Path::new("/etc/passwd"));
+    }
+}